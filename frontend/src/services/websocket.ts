@@ -1,10 +1,13 @@
 // src/services/websocket.ts
 
 export interface WebSocketMessage {
-    type: 'content_update' | 'cursor_position' | 'user_joined' | 'user_left' | 'user_count_update';
+    type: 'hello' | 'welcome' | 'content_update' | 'operation' | 'crdt_operation' | 'crdt_snapshot' | 'conflict' | 'error' | 'cursor_position' | 'user_joined' | 'user_left' | 'presence_snapshot' | 'presence_update' | 'session' | 'missed_operations' | 'metadata' | 'user_count_update';
     payload: any;
 }
 
+// 携带文档版本的消息; 连接时先订阅广播再取快照, 版本不超过已收到版本的消息需丢弃
+const VERSIONED_MESSAGES: WebSocketMessage['type'][] = ['content_update', 'crdt_snapshot', 'missed_operations', 'operation', 'crdt_operation'];
+
 export class WebSocketService {
     private static instance: WebSocketService | null = null;
     private socket: WebSocket | null = null;
@@ -14,6 +17,7 @@ export class WebSocketService {
     private messageHandlers: ((message: WebSocketMessage) => void)[] = [];
     private connectionPromise: Promise<void> | null = null;
     private url: string;
+    private lastVersion = -1;
 
     private constructor(url: string) {
         this.url = url;
@@ -54,6 +58,7 @@ export class WebSocketService {
                 this.socket.onopen = () => {
                     console.log('WebSocket connected successfully');
                     this.reconnectAttempts = 0;
+                    this.lastVersion = -1;
                     this.connectionPromise = null;
                     resolve();
                 };
@@ -62,6 +67,9 @@ export class WebSocketService {
                     try {
                         const message: WebSocketMessage = JSON.parse(event.data);
                         console.log('Received message:', message);
+                        if (this.isStale(message)) {
+                            return;
+                        }
                         this.notifyHandlers(message);
                     } catch (error) {
                         console.error('Error parsing WebSocket message:', error);
@@ -107,6 +115,19 @@ export class WebSocketService {
         };
     }
 
+    // 丢弃已包含在快照中的文档消息, 并记录最新版本
+    private isStale(message: WebSocketMessage): boolean {
+        if (!VERSIONED_MESSAGES.includes(message.type)) {
+            return false;
+        }
+        const version: number = message.payload.version;
+        if (version <= this.lastVersion) {
+            return true;
+        }
+        this.lastVersion = version;
+        return false;
+    }
+
     private notifyHandlers(message: WebSocketMessage): void {
         this.messageHandlers.forEach(handler => handler(message));
     }
@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+/// 任意用户, 用于设置未单独授权的用户的角色
+pub const ANYONE: &str = "*";
+
+/// 文档角色, 按权限从低到高排列
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// 只读
+    Viewer,
+    /// 只读, 可以评论
+    Commenter,
+    /// 可以编辑内容
+    Editor,
+    /// 可以编辑并管理授权
+    Owner,
+}
+
+impl Role {
+    pub fn can_edit(self) -> bool {
+        self >= Role::Editor
+    }
+
+    pub fn can_manage(self) -> bool {
+        self == Role::Owner
+    }
+}
+
+/// 文档的访问控制列表: 用户ID到角色的映射
+///
+/// 默认 (包括未认证时创建的文档) 所有人都是编辑者
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Acl {
+    roles: HashMap<String, Role>,
+}
+
+impl Default for Acl {
+    fn default() -> Self {
+        Self { roles: HashMap::from([(ANYONE.to_string(), Role::Editor)]) }
+    }
+}
+
+impl Acl {
+    /// 只有创建者 (所有者) 可以访问
+    pub fn owned_by(user_id: &str) -> Self {
+        Self { roles: HashMap::from([(user_id.to_string(), Role::Owner)]) }
+    }
+
+    /// 用户的角色, 未单独授权时取 `*` 的角色; 没有任何角色表示无权访问
+    pub fn role(&self, user_id: &str) -> Option<Role> {
+        self.roles.get(user_id).or_else(|| self.roles.get(ANYONE)).copied()
+    }
+
+    /// 授予角色, 会导致文档失去最后一个所有者时拒绝并返回 `false`
+    pub fn grant(&mut self, user_id: &str, role: Role) -> bool {
+        if role != Role::Owner && self.is_last_owner(user_id) {
+            return false;
+        }
+        self.roles.insert(user_id.to_string(), role);
+        true
+    }
+
+    /// 撤销授权, 规则同 [`Acl::grant`]
+    pub fn revoke(&mut self, user_id: &str) -> bool {
+        if self.is_last_owner(user_id) {
+            return false;
+        }
+        self.roles.remove(user_id);
+        true
+    }
+
+    fn is_last_owner(&self, user_id: &str) -> bool {
+        self.roles.get(user_id) == Some(&Role::Owner)
+            && self.roles.values().filter(|role| **role == Role::Owner).count() == 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn role_falls_back_to_anyone() {
+        let mut acl = Acl::default();
+        assert_eq!(acl.role("alice"), Some(Role::Editor));
+
+        assert!(acl.grant("alice", Role::Viewer));
+        assert_eq!(acl.role("alice"), Some(Role::Viewer));
+        assert_eq!(acl.role("bob"), Some(Role::Editor));
+
+        // 撤销 `*` 后未单独授权的用户无权访问
+        assert!(acl.revoke(ANYONE));
+        assert_eq!(acl.role("alice"), Some(Role::Viewer));
+        assert_eq!(acl.role("bob"), None);
+        assert_eq!(Acl::owned_by("alice").role("bob"), None);
+    }
+
+    #[test]
+    fn last_owner_cannot_be_removed() {
+        let mut acl = Acl::owned_by("alice");
+        assert!(!acl.grant("alice", Role::Editor));
+        assert!(!acl.revoke("alice"));
+        assert_eq!(acl.role("alice"), Some(Role::Owner));
+
+        // 有其他所有者时可以降级或撤销
+        assert!(acl.grant("bob", Role::Owner));
+        assert!(acl.grant("alice", Role::Editor));
+        assert_eq!(acl.role("alice"), Some(Role::Editor));
+        assert!(!acl.revoke("bob"));
+        assert!(acl.grant("alice", Role::Owner));
+        assert!(acl.revoke("bob"));
+        assert_eq!(acl.role("bob"), None);
+    }
+}
@@ -0,0 +1,536 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::acl::{Acl, Role};
+use crate::app::{AppState, Author, Document, DocumentKind};
+use crate::auth::{self, Claims};
+use crate::ot::OtError;
+use crate::handler::snapshot_message;
+use crate::protocol::{DocumentMetadata, ServerMessage};
+use crate::storage::DocumentSnapshot;
+
+/// REST 接口的错误, 以 `{"error": "..."}` 返回
+pub enum ApiError {
+    /// 请求内容不合法
+    BadRequest(String),
+    NotFound(String),
+    /// 缺少或无效的认证令牌
+    Unauthorized(String),
+    /// 用户的角色不允许该操作
+    Forbidden(String),
+    /// 请求与当前状态冲突
+    Conflict(String),
+    /// 内容超出大小上限
+    PayloadTooLarge(String),
+    Internal(std::io::Error),
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            ApiError::BadRequest(message) => (StatusCode::BAD_REQUEST, message),
+            ApiError::NotFound(message) => (StatusCode::NOT_FOUND, message),
+            ApiError::Unauthorized(message) => (StatusCode::UNAUTHORIZED, message),
+            ApiError::Forbidden(message) => (StatusCode::FORBIDDEN, message),
+            ApiError::Conflict(message) => (StatusCode::CONFLICT, message),
+            ApiError::PayloadTooLarge(message) => (StatusCode::PAYLOAD_TOO_LARGE, message),
+            ApiError::Internal(e) => {
+                tracing::error!("Internal error: {}", e);
+                (StatusCode::INTERNAL_SERVER_ERROR, "internal server error".to_string())
+            }
+        };
+        (status, Json(serde_json::json!({ "error": message }))).into_response()
+    }
+}
+
+impl From<std::io::Error> for ApiError {
+    fn from(e: std::io::Error) -> Self {
+        ApiError::Internal(e)
+    }
+}
+
+impl From<OtError> for ApiError {
+    fn from(e: OtError) -> Self {
+        ApiError::Conflict(e.to_string())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct VersionInfo {
+    version: u64,
+    // 编辑时间, Unix 毫秒
+    timestamp: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VersionList {
+    current: u64,
+    versions: Vec<VersionInfo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VersionContent {
+    version: u64,
+    content: String,
+}
+
+/// 文档列表中的一项
+#[derive(Debug, Serialize)]
+pub struct DocumentSummary {
+    id: String,
+    kind: DocumentKind,
+    version: u64,
+    #[serde(flatten)]
+    metadata: DocumentMetadata,
+}
+
+impl DocumentSummary {
+    fn new(id: &str, doc: &Document) -> Self {
+        Self {
+            id: id.to_string(),
+            kind: doc.kind(),
+            version: doc.version(),
+            metadata: doc.metadata().clone(),
+        }
+    }
+
+    fn from_snapshot(id: &str, snapshot: DocumentSnapshot) -> Self {
+        Self {
+            id: id.to_string(),
+            kind: snapshot.kind,
+            version: snapshot.version,
+            metadata: snapshot.metadata,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct DocumentContent {
+    #[serde(flatten)]
+    summary: DocumentSummary,
+    content: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CreateDocument {
+    /// 未指定时生成随机ID
+    id: Option<String>,
+    kind: DocumentKind,
+    content: String,
+    title: Option<String>,
+    tags: Option<Vec<String>>,
+    mime_type: Option<String>,
+}
+
+/// 只修改给出的字段
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpdateDocument {
+    content: Option<String>,
+    /// 空字符串表示清除标题
+    title: Option<String>,
+    tags: Option<Vec<String>>,
+    mime_type: Option<String>,
+    /// 内容所基于的版本, 给出时与当前版本不一致则拒绝
+    version: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GrantRequest {
+    role: Role,
+}
+
+async fn open(state: &AppState, doc_id: &str) -> Result<(), ApiError> {
+    if state.open_document(doc_id).await? {
+        Ok(())
+    } else {
+        Err(ApiError::NotFound(format!("document {} not found", doc_id)))
+    }
+}
+
+/// 调用者的身份; 未配置认证时为 `None`, 否则要求有效的令牌
+fn caller(state: &AppState, headers: &HeaderMap) -> Result<Option<Claims>, ApiError> {
+    let Some(authenticator) = state.authenticator() else {
+        return Ok(None);
+    };
+    let token = auth::bearer_token(headers, None)
+        .ok_or_else(|| ApiError::Unauthorized("missing bearer token".to_string()))?;
+    authenticator
+        .verify(token)
+        .map(Some)
+        .map_err(|e| ApiError::Unauthorized(format!("invalid token: {}", e)))
+}
+
+/// 校验调用者在文档中的角色满足 `allowed` 并返回其身份; 未配置认证时不做检查
+fn authorize(
+    state: &AppState,
+    headers: &HeaderMap,
+    doc_id: &str,
+    allowed: fn(Role) -> bool,
+) -> Result<Option<Claims>, ApiError> {
+    let Some(claims) = caller(state, headers)? else {
+        return Ok(None);
+    };
+
+    let role = state.documents.get(doc_id).and_then(|doc| doc.role(&claims.sub));
+    if role.is_some_and(allowed) {
+        Ok(Some(claims))
+    } else {
+        Err(ApiError::Forbidden(format!("user {} is not allowed to do this on document {}", claims.sub, doc_id)))
+    }
+}
+
+fn any_role(_: Role) -> bool {
+    true
+}
+
+/// 文档标题的最大长度 (字符数)
+const MAX_TITLE_LEN: usize = 256;
+const MAX_TAGS: usize = 32;
+const MAX_TAG_LEN: usize = 64;
+
+/// 校验请求中的元数据字段, 返回对应的修改; 没有要修改的字段时返回 `None`
+fn metadata_change(
+    title: Option<String>,
+    tags: Option<Vec<String>>,
+    mime_type: Option<String>,
+) -> Result<Option<impl FnOnce(&mut DocumentMetadata)>, ApiError> {
+    if title.is_none() && tags.is_none() && mime_type.is_none() {
+        return Ok(None);
+    }
+
+    let title = title.map(|title| {
+        let title = title.trim();
+        if title.chars().count() > MAX_TITLE_LEN {
+            return Err(ApiError::BadRequest(format!("title is longer than {} characters", MAX_TITLE_LEN)));
+        }
+        Ok((!title.is_empty()).then(|| title.to_string()))
+    }).transpose()?;
+
+    let tags = tags.map(|tags| {
+        let mut unique: Vec<String> = Vec::new();
+        for tag in tags.iter().map(|tag| tag.trim()).filter(|tag| !tag.is_empty()) {
+            if tag.chars().count() > MAX_TAG_LEN {
+                return Err(ApiError::BadRequest(format!("tag {:?} is longer than {} characters", tag, MAX_TAG_LEN)));
+            }
+            if !unique.iter().any(|existing| existing == tag) {
+                unique.push(tag.to_string());
+            }
+        }
+        if unique.len() > MAX_TAGS {
+            return Err(ApiError::BadRequest(format!("a document can have at most {} tags", MAX_TAGS)));
+        }
+        Ok(unique)
+    }).transpose()?;
+
+    let mime_type = mime_type.map(|mime_type| {
+        let mime_type = mime_type.trim().to_ascii_lowercase();
+        let valid = mime_type.len() <= 127
+            && mime_type.split_once('/').is_some_and(|(kind, subtype)| {
+                !kind.is_empty() && !subtype.is_empty() && !subtype.contains('/')
+            })
+            && !mime_type.contains(char::is_whitespace);
+        if valid {
+            Ok(mime_type)
+        } else {
+            Err(ApiError::BadRequest(format!("invalid mime type {:?}", mime_type)))
+        }
+    }).transpose()?;
+
+    Ok(Some(move |metadata: &mut DocumentMetadata| {
+        if let Some(title) = title {
+            metadata.title = title;
+        }
+        if let Some(tags) = tags {
+            metadata.tags = tags;
+        }
+        if let Some(mime_type) = mime_type {
+            metadata.mime_type = mime_type;
+        }
+    }))
+}
+
+fn check_size(state: &AppState, content: &str) -> Result<(), ApiError> {
+    let max = state.limits().max_document_bytes;
+    if content.len() > max {
+        return Err(ApiError::PayloadTooLarge(format!(
+            "content is {} bytes, the limit is {}",
+            content.len(), max
+        )));
+    }
+    Ok(())
+}
+
+fn document_content(state: &AppState, doc_id: &str) -> Result<Json<DocumentContent>, ApiError> {
+    let doc = state.documents
+        .get(doc_id)
+        .ok_or_else(|| ApiError::NotFound(format!("document {} not found", doc_id)))?;
+    Ok(Json(DocumentContent {
+        summary: DocumentSummary::new(doc_id, &doc),
+        content: doc.content().to_string(),
+    }))
+}
+
+/// `GET /documents`: 列出调用者可以访问的全部文档
+///
+/// 未加载的文档按存储中的快照列出, 版本和最后编辑时间可能略旧于文档实际状态
+pub async fn list_documents(
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<DocumentSummary>>, ApiError> {
+    let claims = caller(&state, &headers)?;
+
+    let visible = |acl: &Acl| claims.as_ref().is_none_or(|claims| acl.role(&claims.sub).is_some());
+    let mut documents = Vec::new();
+    for doc_id in state.list_documents().await? {
+        // 未加载的文档只读取存储中的快照, 不加载到内存
+        let loaded = state.documents.get(&doc_id)
+            .map(|doc| visible(doc.acl()).then(|| DocumentSummary::new(&doc_id, &doc)));
+        let summary = match loaded {
+            Some(summary) => summary,
+            None => state.stored_snapshot(&doc_id).await?
+                .filter(|snapshot| visible(&snapshot.acl))
+                .map(|snapshot| DocumentSummary::from_snapshot(&doc_id, snapshot)),
+        };
+        documents.extend(summary);
+    }
+    Ok(Json(documents))
+}
+
+/// `POST /documents`: 新建文档, 已认证的调用者成为所有者
+pub async fn create_document(
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<CreateDocument>,
+) -> Result<(StatusCode, Json<DocumentContent>), ApiError> {
+    let claims = caller(&state, &headers)?;
+    let change = metadata_change(request.title, request.tags, request.mime_type)?;
+    check_size(&state, &request.content)?;
+    let doc_id = request.id.unwrap_or_else(|| Uuid::new_v4().to_string());
+    if doc_id.is_empty() {
+        return Err(ApiError::BadRequest("document id must not be empty".to_string()));
+    }
+
+    let creator = claims.as_ref().map(|claims| claims.sub.as_str());
+    if !state.create_document(&doc_id, request.kind, creator).await? {
+        return Err(ApiError::Conflict(format!("document {} already exists", doc_id)));
+    }
+    if let Some(mut doc) = state.documents.get_mut(&doc_id) {
+        if let Some(change) = change {
+            doc.update_metadata(change);
+        }
+        if !request.content.is_empty() {
+            doc.update(&request.content, Author { user_id: creator, connection_id: None })?;
+        }
+    }
+    state.mark_dirty(&doc_id);
+
+    Ok((StatusCode::CREATED, document_content(&state, &doc_id)?))
+}
+
+/// `GET /documents/{id}`: 文档的当前内容
+pub async fn get_document(
+    Path(doc_id): Path<String>,
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<DocumentContent>, ApiError> {
+    open(&state, &doc_id).await?;
+    authorize(&state, &headers, &doc_id, any_role)?;
+    document_content(&state, &doc_id)
+}
+
+/// `PUT /documents/{id}`: 替换内容和/或修改元数据; 内容的修改像 WebSocket 编辑一样广播
+pub async fn update_document(
+    Path(doc_id): Path<String>,
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<UpdateDocument>,
+) -> Result<Json<DocumentContent>, ApiError> {
+    open(&state, &doc_id).await?;
+    let claims = authorize(&state, &headers, &doc_id, Role::can_edit)?;
+    let author = Author { user_id: claims.as_ref().map(|claims| claims.sub.as_str()), connection_id: None };
+    let change = metadata_change(request.title, request.tags, request.mime_type)?;
+    if let Some(content) = &request.content {
+        check_size(&state, content)?;
+    }
+
+    // 与 WebSocket 编辑一样在持有文档锁时广播, 新连接的快照与广播不会错开
+    {
+        let Some(mut doc) = state.documents.get_mut(&doc_id) else {
+            return Err(ApiError::NotFound(format!("document {} not found", doc_id)));
+        };
+        if let Some(base_version) = request.version.filter(|v| *v != doc.version()) {
+            return Err(ApiError::Conflict(format!(
+                "document {} is at version {}, not {}",
+                doc_id, doc.version(), base_version
+            )));
+        }
+        if let Some(content) = request.content {
+            let diff = doc.update(&content, author)?;
+            state.transform_selections(&doc_id, &diff);
+            state.broadcast(&doc_id, snapshot_message(&doc, None));
+        }
+        if let Some(change) = change {
+            doc.update_metadata(change);
+            state.broadcast(&doc_id, ServerMessage::Metadata(doc.metadata().clone()));
+        }
+    }
+    state.mark_dirty(&doc_id);
+
+    tracing::info!(doc_id = %doc_id, "Document updated via REST");
+    document_content(&state, &doc_id)
+}
+
+/// `DELETE /documents/{id}`: 删除文档及其历史, 正在编辑的连接将被断开; 仅所有者可调用
+pub async fn delete_document(
+    Path(doc_id): Path<String>,
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+) -> Result<StatusCode, ApiError> {
+    open(&state, &doc_id).await?;
+    authorize(&state, &headers, &doc_id, Role::can_manage)?;
+
+    state.delete_document(&doc_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `GET /documents/{id}/versions`: 列出文档的全部历史版本
+pub async fn list_versions(
+    Path(doc_id): Path<String>,
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<VersionList>, ApiError> {
+    open(&state, &doc_id).await?;
+    authorize(&state, &headers, &doc_id, any_role)?;
+
+    let versions = state.document_history(&doc_id).await?
+        .into_iter()
+        .map(|entry| VersionInfo { version: entry.version, timestamp: entry.timestamp })
+        .collect();
+    let current = state.documents.get(&doc_id).map_or(0, |doc| doc.version());
+    Ok(Json(VersionList { current, versions }))
+}
+
+/// `GET /documents/{id}/versions/{version}`: 文档在指定版本时的内容
+pub async fn get_version(
+    Path((doc_id, version)): Path<(String, u64)>,
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<VersionContent>, ApiError> {
+    open(&state, &doc_id).await?;
+    authorize(&state, &headers, &doc_id, any_role)?;
+
+    let doc = state.document_at(&doc_id, version).await?
+        .ok_or_else(|| ApiError::NotFound(format!("version {} of document {} not found", version, doc_id)))?;
+    Ok(Json(VersionContent { version, content: doc.content().to_string() }))
+}
+
+/// `POST /documents/{id}/versions/{version}/restore`: 以旧版本的内容创建一个新版本,
+/// 并像 WebSocket 编辑一样广播给正在编辑的用户
+pub async fn restore_version(
+    Path((doc_id, version)): Path<(String, u64)>,
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<VersionContent>, ApiError> {
+    open(&state, &doc_id).await?;
+    let claims = authorize(&state, &headers, &doc_id, Role::can_edit)?;
+    let author = Author { user_id: claims.as_ref().map(|claims| claims.sub.as_str()), connection_id: None };
+
+    let old = state.document_at(&doc_id, version).await?
+        .ok_or_else(|| ApiError::NotFound(format!("version {} of document {} not found", version, doc_id)))?;
+
+    let new_version = {
+        let mut doc = state.documents.get_mut(&doc_id)
+            .ok_or_else(|| ApiError::NotFound(format!("document {} not found", doc_id)))?;
+        let diff = doc.update(old.content(), author)?;
+        state.transform_selections(&doc_id, &diff);
+        state.broadcast(&doc_id, snapshot_message(&doc, None));
+        doc.version()
+    };
+    state.mark_dirty(&doc_id);
+
+    tracing::info!(doc_id = %doc_id, "Restored version {} as version {}", version, new_version);
+    Ok(Json(VersionContent { version: new_version, content: old.content().to_string() }))
+}
+
+/// `GET /documents/{id}/acl`: 文档的访问控制列表
+pub async fn get_acl(
+    Path(doc_id): Path<String>,
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Acl>, ApiError> {
+    open(&state, &doc_id).await?;
+    authorize(&state, &headers, &doc_id, any_role)?;
+    Ok(acl_of(&state, &doc_id))
+}
+
+/// `PUT /documents/{id}/acl/{user_id}`: 授予用户角色, `*` 表示其他所有用户, 仅所有者可调用
+pub async fn grant_role(
+    Path((doc_id, user_id)): Path<(String, String)>,
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<GrantRequest>,
+) -> Result<Json<Acl>, ApiError> {
+    open(&state, &doc_id).await?;
+    authorize(&state, &headers, &doc_id, Role::can_manage)?;
+
+    update_acl(&state, &doc_id, |acl| acl.grant(&user_id, request.role))?;
+    tracing::info!(doc_id = %doc_id, "Granted {:?} to user {}", request.role, user_id);
+    Ok(acl_of(&state, &doc_id))
+}
+
+/// `DELETE /documents/{id}/acl/{user_id}`: 撤销用户的角色, 仅所有者可调用
+pub async fn revoke_role(
+    Path((doc_id, user_id)): Path<(String, String)>,
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Acl>, ApiError> {
+    open(&state, &doc_id).await?;
+    authorize(&state, &headers, &doc_id, Role::can_manage)?;
+
+    update_acl(&state, &doc_id, |acl| acl.revoke(&user_id))?;
+    tracing::info!(doc_id = %doc_id, "Revoked role of user {}", user_id);
+    Ok(acl_of(&state, &doc_id))
+}
+
+fn update_acl(state: &AppState, doc_id: &str, change: impl FnOnce(&mut Acl) -> bool) -> Result<(), ApiError> {
+    let changed = state.documents.get_mut(doc_id).is_some_and(|mut doc| doc.update_acl(change));
+    if !changed {
+        return Err(ApiError::Conflict("document must keep at least one owner".to_string()));
+    }
+    state.mark_dirty(doc_id);
+    Ok(())
+}
+
+fn acl_of(state: &AppState, doc_id: &str) -> Json<Acl> {
+    Json(state.documents.get(doc_id).map(|doc| doc.acl().clone()).unwrap_or_default())
+}
+
+/// `GET /metrics`: Prometheus 文本格式的运行指标
+pub async fn metrics(State(state): State<Arc<AppState>>) -> String {
+    let metrics = state.metrics();
+    format!(
+        "# TYPE realtime_editor_broadcast_lag_events_total counter\n\
+         realtime_editor_broadcast_lag_events_total {}\n\
+         # TYPE realtime_editor_broadcast_lagged_messages_total counter\n\
+         realtime_editor_broadcast_lagged_messages_total {}\n\
+         # TYPE realtime_editor_rate_limited_messages_total counter\n\
+         realtime_editor_rate_limited_messages_total {}\n\
+         # TYPE realtime_editor_rate_limit_disconnects_total counter\n\
+         realtime_editor_rate_limit_disconnects_total {}\n",
+        metrics.broadcast_lag_events.load(Ordering::Relaxed),
+        metrics.broadcast_lagged_messages.load(Ordering::Relaxed),
+        metrics.rate_limited_messages.load(Ordering::Relaxed),
+        metrics.rate_limit_disconnects.load(Ordering::Relaxed),
+    )
+}
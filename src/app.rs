@@ -1,47 +1,457 @@
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::sync::broadcast;
-use dashmap::DashMap;
+use tokio::sync::{broadcast, Mutex, Notify};
+use dashmap::{mapref::entry::Entry, DashMap, DashSet};
+use serde::{Deserialize, Serialize};
+use crate::acl::{Acl, Role};
+use crate::auth::Authenticator;
+use crate::config::{Config, ConnectionConfig, LimitsConfig, RateLimitConfig};
+use crate::crdt::{CrdtOp, Element, Rga};
+use crate::ot::{Operation, OtError, Selection};
+use crate::protocol::{DocumentMetadata, MissedOperation, ServerMessage, UserPresence};
+use crate::storage::{DocumentSnapshot, DocumentStore, LogEntry, LoggedOp};
+
+/// 服务器自身作为 CRDT 站点时使用的站点ID
+const SERVER_SITE_ID: &str = "server";
+
+/// 未指定颜色的用户按ID从中选取
+const USER_COLORS: &[&str] = &[
+    "#e6194b", "#3cb44b", "#4363d8", "#f58231", "#911eb4", "#42d4f4", "#f032e6", "#469990",
+];
+const MAX_NAME_LEN: usize = 64;
+
+/// 延迟与上次广播的值相差至少这么多才再次广播
+const LATENCY_BROADCAST_THRESHOLD: std::time::Duration = std::time::Duration::from_millis(50);
+/// 同一连接两次广播延迟的最小间隔
+const LATENCY_BROADCAST_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
 
 #[derive(Debug, Clone)]
 pub struct User {
+    // 稳定的用户身份, 同一用户的多个连接相同
     id: String,
+    // 每个连接唯一, 也用作 CRDT 站点ID
+    connection_id: String,
+    // 用户当前所在的文档
+    doc_id: String,
+    // 显示名称和光标颜色, 未指定时自动生成
+    name: String,
+    color: String,
     connected_at: std::time::SystemTime,
     last_activity: std::time::SystemTime,
+    // 最近上报的光标/选区, 已转换到文档当前版本
+    selection: Option<Selection>,
+    // 空闲超过 IdlePolicy::away_after 后标记为离开, 再次活动时恢复
+    away: bool,
+    // 通知连接断开, 如空闲超时
+    disconnect: Arc<Notify>,
+    // 最近一次 ping/pong 的往返延迟
+    latency: Option<std::time::Duration>,
+    // 最近一次广播的延迟及广播时间
+    latency_broadcast: Option<(std::time::Duration, std::time::Instant)>,
+    // 断开后凭此令牌恢复会话, 每个连接重新生成, 只能使用一次
+    resume_token: String,
 }
 
 impl User {
-    pub fn new(id: String) -> Self {
+    pub fn new(
+        id: String,
+        connection_id: String,
+        doc_id: String,
+        name: Option<String>,
+        color: Option<String>,
+    ) -> Self {
+        let name = name
+            .map(|name| name.trim().chars().take(MAX_NAME_LEN).collect::<String>())
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| format!("Guest {}", &id[..id.len().min(4)]));
+        // 只接受 #rrggbb 形式的颜色
+        let color = color
+            .filter(|c| c.len() == 7 && c.starts_with('#') && c[1..].chars().all(|c| c.is_ascii_hexdigit()))
+            .unwrap_or_else(|| {
+                let hash = id.bytes().fold(0usize, |h, b| h.wrapping_mul(31).wrapping_add(b as usize));
+                USER_COLORS[hash % USER_COLORS.len()].to_string()
+            });
         Self {
             id,
+            connection_id,
+            doc_id,
+            name,
+            color,
             connected_at: std::time::SystemTime::now(),
             last_activity: std::time::SystemTime::now(),
+            selection: None,
+            away: false,
+            disconnect: Arc::new(Notify::new()),
+            latency: None,
+            latency_broadcast: None,
+            resume_token: uuid::Uuid::new_v4().to_string(),
+        }
+    }
+
+    pub fn resume_token(&self) -> &str {
+        &self.resume_token
+    }
+
+    /// 被通知时连接应当断开
+    pub fn disconnect_signal(&self) -> Arc<Notify> {
+        self.disconnect.clone()
+    }
+
+    pub fn presence(&self) -> UserPresence {
+        UserPresence {
+            user_id: self.id.clone(),
+            connection_id: self.connection_id.clone(),
+            name: self.name.clone(),
+            color: self.color.clone(),
+            connected_at: unix_millis(self.connected_at),
+            selection: self.selection,
+            away: self.away,
+            latency_ms: self.latency.map(|latency| latency.as_millis() as u64),
         }
     }
 }
 
+/// 断开后保留一段时间的会话, 重连时凭令牌恢复身份
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub user_id: String,
+    pub connection_id: String,
+    pub doc_id: String,
+    pub name: String,
+    pub color: String,
+    expires_at: std::time::SystemTime,
+}
+
+/// 文档后端, 创建文档时选择
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DocumentKind {
+    /// 服务器权威版本, 编辑以 OT 操作转换后提交
+    #[default]
+    Ot,
+    /// 序列 CRDT, 各站点的编辑无需转换即可确定性合并
+    Crdt,
+}
+
+#[derive(Debug, Clone)]
+enum Backend {
+    // 已提交的操作, operations[n] 把版本 first_version + n 变为下一版本;
+    // authors[n] 为提交该操作的连接ID, 从存储恢复的操作没有记录
+    Ot { operations: Vec<Operation>, authors: Vec<Option<String>>, first_version: u64 },
+    Crdt(Rga),
+}
+
 #[derive(Debug, Clone)]
 pub struct Document {
     content: String,
     version: u64,
-    last_modified: std::time::SystemTime,
+    backend: Backend,
+    // 尚未写入存储的编辑记录
+    unsaved: Vec<LogEntry>,
+    // 存储中最新快照的版本, 尚未保存过快照时为空
+    snapshot_version: Option<u64>,
+    acl: Acl,
+    metadata: DocumentMetadata,
 }
 
 impl Default for Document {
     fn default() -> Self {
+        Self::new(DocumentKind::default())
+    }
+}
+
+impl Document {
+    pub fn new(kind: DocumentKind) -> Self {
+        let backend = match kind {
+            DocumentKind::Ot => Backend::Ot { operations: Vec::new(), authors: Vec::new(), first_version: 0 },
+            DocumentKind::Crdt => Backend::Crdt(Rga::new(SERVER_SITE_ID)),
+        };
+        let now = unix_millis(std::time::SystemTime::now());
         Self {
             content: String::new(),
             version: 0,
-            last_modified: std::time::SystemTime::now(),
+            backend,
+            unsaved: Vec::new(),
+            snapshot_version: None,
+            acl: Acl::default(),
+            metadata: DocumentMetadata { created_at: now, last_modified: now, ..Default::default() },
         }
     }
-}
 
-impl Document {
-    pub fn update(&mut self, content: &str) {
+    /// 从存储的快照恢复文档
+    ///
+    /// OT 文档快照之前的操作不在内存中, 基于更早版本的操作将被拒绝
+    pub fn from_snapshot(snapshot: DocumentSnapshot) -> Self {
+        let backend = match snapshot.kind {
+            DocumentKind::Ot => Backend::Ot {
+                operations: Vec::new(),
+                authors: Vec::new(),
+                first_version: snapshot.version,
+            },
+            DocumentKind::Crdt => Backend::Crdt(Rga::from_elements(
+                SERVER_SITE_ID,
+                snapshot.elements.unwrap_or_default(),
+            )),
+        };
+        Self {
+            content: snapshot.content,
+            version: snapshot.version,
+            backend,
+            unsaved: Vec::new(),
+            snapshot_version: Some(snapshot.version),
+            acl: snapshot.acl,
+            metadata: snapshot.metadata,
+        }
+    }
+
+    /// 重放快照之后的编辑记录, 已包含在当前版本中的记录会被跳过
+    pub fn replay(&mut self, entry: LogEntry) -> Result<(), OtError> {
+        if entry.version <= self.version {
+            return Ok(());
+        }
+        match (&mut self.backend, entry.op) {
+            (Backend::Ot { operations, authors, .. }, LoggedOp::Ot { ops }) => {
+                if entry.version != self.version + 1 {
+                    return Err(OtError::UnknownVersion { base: entry.version - 1, current: self.version });
+                }
+                self.content = ops.apply(&self.content)?;
+                operations.push(ops);
+                authors.push(None);
+            }
+            (Backend::Crdt(rga), LoggedOp::Crdt { ops }) => {
+                rga.merge(ops)?;
+                self.content = rga.text();
+            }
+            _ => return Err(OtError::Unsupported),
+        }
+        self.version = entry.version;
+        self.metadata.last_modified = entry.timestamp;
+        if entry.editor.is_some() {
+            self.metadata.last_editor = entry.editor;
+        }
+        Ok(())
+    }
+
+    /// 取出待写入存储的内容: 未保存的编辑记录, 以及距上次快照已超过
+    /// `snapshot_interval` 个版本 (或从未保存过) 时的新快照
+    pub fn take_persist_batch(&mut self, snapshot_interval: u64) -> (Vec<LogEntry>, Option<DocumentSnapshot>) {
+        let entries = std::mem::take(&mut self.unsaved);
+        let snapshot_due = self
+            .snapshot_version
+            .is_none_or(|version| self.version - version >= snapshot_interval);
+        let snapshot = snapshot_due.then(|| {
+            self.snapshot_version = Some(self.version);
+            self.trim_operations(snapshot_interval as usize);
+            self.snapshot()
+        });
+        (entries, snapshot)
+    }
+
+    /// 只在内存中保留最近 `keep` 个 OT 操作, 基于更早版本的编辑会被拒绝为 `VersionTooOld`
+    fn trim_operations(&mut self, keep: usize) {
+        if let Backend::Ot { operations, authors, first_version } = &mut self.backend {
+            let excess = operations.len().saturating_sub(keep);
+            operations.drain(..excess);
+            authors.drain(..excess);
+            *first_version += excess as u64;
+        }
+    }
+
+    /// 写入存储失败时调用, 下次写入时强制保存完整快照以补上丢失的记录
+    pub fn invalidate_snapshot(&mut self) {
+        self.snapshot_version = None;
+    }
+
+    pub fn snapshot(&self) -> DocumentSnapshot {
+        DocumentSnapshot {
+            kind: self.kind(),
+            content: self.content.clone(),
+            version: self.version,
+            elements: self.crdt_elements(),
+            acl: self.acl.clone(),
+            metadata: self.metadata.clone(),
+        }
+    }
+
+    pub fn acl(&self) -> &Acl {
+        &self.acl
+    }
+
+    /// 用户在文档中的角色, `None` 表示无权访问
+    pub fn role(&self, user_id: &str) -> Option<Role> {
+        self.acl.role(user_id)
+    }
+
+    /// 修改访问控制列表, `change` 返回是否修改成功
+    ///
+    /// 访问控制列表只保存在快照中, 修改后下次写入时保存完整快照
+    pub fn update_acl(&mut self, change: impl FnOnce(&mut Acl) -> bool) -> bool {
+        let changed = change(&mut self.acl);
+        if changed {
+            self.snapshot_version = None;
+        }
+        changed
+    }
+
+    pub fn metadata(&self) -> &DocumentMetadata {
+        &self.metadata
+    }
+
+    /// 修改标题、标签等元数据, 与访问控制列表一样只保存在快照中
+    pub fn update_metadata(&mut self, change: impl FnOnce(&mut DocumentMetadata)) {
+        change(&mut self.metadata);
+        self.snapshot_version = None;
+    }
+
+    pub fn kind(&self) -> DocumentKind {
+        match self.backend {
+            Backend::Ot { .. } => DocumentKind::Ot,
+            Backend::Crdt(_) => DocumentKind::Crdt,
+        }
+    }
+
+    /// 整体替换内容, 返回对应的差异操作
+    ///
+    /// OT 文档以差异操作的形式记录, 使并发的操作仍可转换;
+    /// CRDT 文档由服务器站点生成对应的插入/删除, 时钟耗尽时返回错误, 文档不变
+    pub fn update(&mut self, content: &str, author: Author) -> Result<Operation, OtError> {
+        let diff = Operation::diff(&self.content, content);
+        let op = match &mut self.backend {
+            Backend::Ot { operations, authors, .. } => {
+                operations.push(diff.clone());
+                authors.push(author.connection_id.map(str::to_string));
+                self.version += 1;
+                LoggedOp::Ot { ops: diff.clone() }
+            }
+            Backend::Crdt(rga) => {
+                let ops = rga.replace(content)?;
+                self.version += ops.len() as u64;
+                LoggedOp::Crdt { ops }
+            }
+        };
         self.content = content.to_string();
+        self.record(op, author);
+        Ok(diff)
+    }
+
+    /// 应用基于 `base_version` 的操作: 先对其后已提交的操作做转换, 再应用
+    ///
+    /// 返回转换后实际生效的操作; 应用后内容超过 `max_bytes` 时拒绝, 文档不变
+    pub fn apply_operation(
+        &mut self,
+        base_version: u64,
+        mut operation: Operation,
+        author: Author,
+        max_bytes: usize,
+    ) -> Result<Operation, OtError> {
+        let Backend::Ot { operations, authors, first_version } = &mut self.backend else {
+            return Err(OtError::Unsupported);
+        };
+        if base_version > self.version {
+            return Err(OtError::UnknownVersion { base: base_version, current: self.version });
+        }
+        if base_version < *first_version {
+            return Err(OtError::VersionTooOld { base: base_version, oldest: *first_version });
+        }
+
+        for committed in &operations[(base_version - *first_version) as usize..] {
+            // 已提交的操作优先, 同一位置的插入排在前面
+            let (_, transformed) = Operation::transform(committed, &operation)?;
+            operation = transformed;
+        }
+
+        let content = operation.apply(&self.content)?;
+        if content.len() > max_bytes {
+            return Err(OtError::TooLarge { len: content.len(), max: max_bytes });
+        }
+        self.content = content;
+        operations.push(operation.clone());
+        authors.push(author.connection_id.map(str::to_string));
         self.version += 1;
-        self.last_modified = std::time::SystemTime::now();
-    } 
+        self.record(LoggedOp::Ot { ops: operation.clone() }, author);
+        Ok(operation)
+    }
+
+    /// 合并 CRDT 操作, 返回是否有操作生效; 非 CRDT 文档返回 `OtError::Unsupported`
+    ///
+    /// CRDT 文档的版本号为已合并的操作数
+    pub fn merge_crdt(&mut self, ops: Vec<CrdtOp>, author: Author) -> Result<bool, OtError> {
+        let Backend::Crdt(rga) = &mut self.backend else {
+            return Err(OtError::Unsupported);
+        };
+        let applied = rga.merge(ops.clone())?;
+        if applied > 0 {
+            self.content = rga.text();
+            self.version += applied as u64;
+            // 记录收到的全部操作, 重放时合并是幂等的
+            self.record(LoggedOp::Crdt { ops }, author);
+        }
+        Ok(applied > 0)
+    }
+
+    /// 把基于 `base_version` 的选区转换到当前版本
+    ///
+    /// CRDT 文档不按版本保留操作, 选区视为基于当前版本
+    pub fn transform_selection(&self, base_version: Option<u64>, selection: Selection) -> Result<Selection, OtError> {
+        let selection = match (&self.backend, base_version) {
+            (Backend::Ot { operations, first_version, .. }, Some(base)) => {
+                if base > self.version {
+                    return Err(OtError::UnknownVersion { base, current: self.version });
+                }
+                if base < *first_version {
+                    return Err(OtError::VersionTooOld { base, oldest: *first_version });
+                }
+                operations[(base - *first_version) as usize..]
+                    .iter()
+                    .fold(selection, |selection, op| selection.transform(op))
+            }
+            _ => selection,
+        };
+        Ok(selection.clamp(self.content.chars().count()))
+    }
+
+    /// 版本 `since` 之后提交的全部操作, 供恢复的会话补齐; `connection_id` 提交的操作标记为自己的
+    ///
+    /// 不是 OT 文档或所需的操作已不在内存中时返回 `None`, 此时应改发完整快照
+    pub fn operations_since(&self, since: u64, connection_id: &str) -> Option<Vec<MissedOperation>> {
+        let Backend::Ot { operations, authors, first_version } = &self.backend else {
+            return None;
+        };
+        if since < *first_version || since > self.version {
+            return None;
+        }
+        let start = (since - *first_version) as usize;
+        let missed = operations[start..]
+            .iter()
+            .zip(&authors[start..])
+            .zip(since + 1..)
+            .map(|((ops, author), version)| MissedOperation {
+                ops: ops.clone(),
+                version,
+                own: author.as_deref() == Some(connection_id),
+            })
+            .collect();
+        Some(missed)
+    }
+
+    /// 记录一次已生效的编辑, 等待写入存储; 编辑时间和编辑者随日志保存
+    fn record(&mut self, op: LoggedOp, author: Author) {
+        let entry = LogEntry::new(self.version, op, author.user_id.map(str::to_string));
+        self.metadata.last_modified = entry.timestamp;
+        if entry.editor.is_some() {
+            self.metadata.last_editor = entry.editor.clone();
+        }
+        self.unsaved.push(entry);
+    }
+
+    /// CRDT 文档的全部元素 (含墓碑), 新连接的站点据此重建状态
+    pub fn crdt_elements(&self) -> Option<Vec<Element>> {
+        match &self.backend {
+            Backend::Crdt(rga) => Some(rga.elements()),
+            Backend::Ot { .. } => None,
+        }
+    }
 
     pub fn version(&self) -> u64 {
         self.version
@@ -52,53 +462,746 @@ impl Document {
     }
 }
 
+/// 编辑的来源
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Author<'a> {
+    /// 记录为文档的最后编辑者
+    pub user_id: Option<&'a str>,
+    /// 提交编辑的连接, 恢复会话时据此识别自己的操作; REST 编辑没有连接
+    pub connection_id: Option<&'a str>,
+}
+
+/// 新建的空文档; 已认证的创建者成为唯一的所有者
+fn new_document(kind: DocumentKind, creator: Option<&str>) -> Document {
+    let mut doc = Document::new(kind);
+    if let Some(creator) = creator {
+        doc.update_acl(|acl| {
+            *acl = Acl::owned_by(creator);
+            true
+        });
+        doc.metadata.creator = Some(creator.to_string());
+    }
+    doc
+}
+
+fn unix_millis(time: std::time::SystemTime) -> u64 {
+    time.duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+/// 文档写入存储的时机
+#[derive(Debug, Clone, Copy)]
+pub enum FlushPolicy {
+    /// 每次编辑后立即写入
+    OnUpdate,
+    /// 首次编辑后等待一段时间, 合并期间的编辑一起写入
+    Debounce(std::time::Duration),
+}
+
+/// 空闲用户的处理
+#[derive(Debug, Clone, Copy)]
+pub struct IdlePolicy {
+    /// 无活动多久后标记为离开
+    pub away_after: std::time::Duration,
+    /// 无活动多久后断开连接
+    pub disconnect_after: std::time::Duration,
+}
+
+impl Default for IdlePolicy {
+    fn default() -> Self {
+        Self {
+            away_after: std::time::Duration::from_secs(5 * 60),
+            disconnect_after: std::time::Duration::from_secs(60 * 60),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct AppState {
     // 文档ID到内容的映射
     pub documents: Arc<DashMap<String, Document>>,
-    // 用户列表
+    // 在线用户, 以连接ID为键
     users: Arc<DashMap<String, User>>,
-    // 广播通道用于实时消息
-    pub tx: broadcast::Sender<String>,
+    // 已断开、可恢复的会话, 以恢复令牌为键
+    sessions: Arc<DashMap<String, Session>>,
+    // 会话断开后保留多久
+    session_ttl: std::time::Duration,
+    // 每个文档独立的广播通道, 按需创建, 最后一个订阅者离开时释放
+    channels: Arc<DashMap<String, broadcast::Sender<Arc<ServerMessage>>>>,
+    // 每个广播通道的容量
+    channel_capacity: usize,
+    // 文档持久化存储
+    store: Arc<dyn DocumentStore>,
+    // 有未写入编辑的文档
+    dirty: Arc<DashSet<String>>,
+    // 每个文档的写入锁: 同一文档的写入依次进行, 日志追加不会乱序
+    flush_locks: Arc<DashMap<String, Arc<Mutex<()>>>>,
+    // 通知后台任务写入存储
+    flush_notify: Arc<Notify>,
+    flush_policy: FlushPolicy,
+    // 每隔多少个版本保存一次快照并截断日志
+    snapshot_interval: u64,
+    // 连接令牌校验, 未配置时不要求认证
+    auth: Option<Arc<Authenticator>>,
+    idle_policy: IdlePolicy,
+    connection: ConnectionConfig,
+    rate_limit: RateLimitConfig,
+    limits: LimitsConfig,
+    metrics: Arc<Metrics>,
+}
+
+/// 运行指标
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// 客户端落后于广播通道而被重新发送快照的次数
+    pub broadcast_lag_events: AtomicU64,
+    /// 因落后而跳过的广播消息总数
+    pub broadcast_lagged_messages: AtomicU64,
+    /// 因超出限流被丢弃的消息数
+    pub rate_limited_messages: AtomicU64,
+    /// 因持续超出限流被断开的连接数
+    pub rate_limit_disconnects: AtomicU64,
 }
 
 impl AppState {
-    pub fn new() -> Self {
-        let (tx, _) = broadcast::channel(1000);
+    pub fn new(
+        store: Arc<dyn DocumentStore>,
+        config: &Config,
+        auth: Option<Authenticator>,
+    ) -> Self {
         Self { 
             documents: Arc::new(DashMap::new()), 
             users: Arc::new(DashMap::new()), 
-            tx, 
+            sessions: Arc::new(DashMap::new()),
+            session_ttl: config.connection.session_ttl(),
+            channels: Arc::new(DashMap::new()),
+            channel_capacity: config.connection.broadcast_capacity,
+            store,
+            dirty: Arc::new(DashSet::new()),
+            flush_locks: Arc::new(DashMap::new()),
+            flush_notify: Arc::new(Notify::new()),
+            flush_policy: config.flush_policy(),
+            snapshot_interval: config.storage.snapshot_interval,
+            auth: auth.map(Arc::new),
+            idle_policy: config.idle_policy(),
+            connection: config.connection,
+            rate_limit: config.rate_limit,
+            limits: config.limits,
+            metrics: Arc::new(Metrics::default()),
         }
     }
 
-    pub fn add_user(&self, user_id: String) -> usize {
-        let user = User {
-            id: user_id.clone(),
-            connected_at: std::time::SystemTime::now(),
-            last_activity: std::time::SystemTime::now(),
+    /// 连接相关的配置, 如心跳间隔
+    pub fn connection_config(&self) -> &ConnectionConfig {
+        &self.connection
+    }
+
+    pub fn rate_limit_config(&self) -> &RateLimitConfig {
+        &self.rate_limit
+    }
+
+    /// 消息与文档的大小上限
+    pub fn limits(&self) -> &LimitsConfig {
+        &self.limits
+    }
+
+    pub fn authenticator(&self) -> Option<&Authenticator> {
+        self.auth.as_deref()
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// 记录一次广播落后, `skipped` 为跳过的消息数
+    pub fn record_lag(&self, skipped: u64) {
+        self.metrics.broadcast_lag_events.fetch_add(1, Ordering::Relaxed);
+        self.metrics.broadcast_lagged_messages.fetch_add(skipped, Ordering::Relaxed);
+    }
+
+    /// 首次访问时从存储恢复文档, 存储中没有则以 `kind` 新建; 返回文档实际的后端
+    ///
+    /// 已认证的用户新建的文档以其为所有者, 并立即保存访问控制列表
+    pub async fn load_document(
+        &self,
+        doc_id: &str,
+        kind: DocumentKind,
+        creator: Option<&str>,
+    ) -> std::io::Result<DocumentKind> {
+        if let Some(doc) = self.documents.get(doc_id) {
+            return Ok(doc.kind());
+        }
+
+        let (doc, created) = match self.recover(doc_id).await? {
+            Some(doc) => (doc, false),
+            None => (new_document(kind, creator), creator.is_some()),
         };
+        // 并发加载时以先插入的为准
+        let kind = self.documents.entry(doc_id.to_string()).or_insert(doc).kind();
+        if created {
+            self.mark_dirty(doc_id);
+        }
+        Ok(kind)
+    }
 
-        self.users.insert(user_id, user);
-        self.users.len()
+    /// 打开已存在的文档 (必要时从存储恢复), 文档不存在时返回 `false` 且不创建
+    pub async fn open_document(&self, doc_id: &str) -> std::io::Result<bool> {
+        if self.documents.contains_key(doc_id) {
+            return Ok(true);
+        }
+
+        match self.recover(doc_id).await? {
+            Some(doc) => {
+                self.documents.entry(doc_id.to_string()).or_insert(doc);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// 新建文档, 已存在 (内存或存储中) 时返回 `false`
+    pub async fn create_document(
+        &self,
+        doc_id: &str,
+        kind: DocumentKind,
+        creator: Option<&str>,
+    ) -> std::io::Result<bool> {
+        if self.open_document(doc_id).await? {
+            return Ok(false);
+        }
+        match self.documents.entry(doc_id.to_string()) {
+            Entry::Occupied(_) => return Ok(false),
+            Entry::Vacant(entry) => {
+                entry.insert(new_document(kind, creator));
+            }
+        }
+        self.mark_dirty(doc_id);
+        tracing::info!(doc_id = %doc_id, "Document created");
+        Ok(true)
+    }
+
+    /// 全部文档的ID (存储中的和尚未写入存储的), 按ID排序
+    pub async fn list_documents(&self) -> std::io::Result<Vec<String>> {
+        let store = self.store.clone();
+        let stored = tokio::task::spawn_blocking(move || store.list())
+            .await
+            .map_err(std::io::Error::other)??;
+        let mut doc_ids: BTreeSet<String> = stored.into_iter().collect();
+        doc_ids.extend(self.documents.iter().map(|doc| doc.key().clone()));
+        Ok(doc_ids.into_iter().collect())
+    }
+
+    /// 读取存储中的文档快照, 不加载文档; 快照之后的日志不会重放, 版本等信息可能略旧
+    pub async fn stored_snapshot(&self, doc_id: &str) -> std::io::Result<Option<DocumentSnapshot>> {
+        let store = self.store.clone();
+        let id = doc_id.to_string();
+        tokio::task::spawn_blocking(move || store.load(&id))
+            .await
+            .map_err(std::io::Error::other)?
+    }
+
+    /// 删除文档及其存储; 关闭文档的广播通道, 使其中的连接断开
+    pub async fn delete_document(&self, doc_id: &str) -> std::io::Result<()> {
+        // 等待进行中的写入完成, 避免其在删除后重新写入文档
+        let lock = self.flush_lock(doc_id);
+        let _guard = lock.lock().await;
+        self.documents.remove(doc_id);
+        self.dirty.remove(doc_id);
+        self.channels.remove(doc_id);
+
+        let store = self.store.clone();
+        let id = doc_id.to_string();
+        tokio::task::spawn_blocking(move || store.delete(&id))
+            .await
+            .map_err(std::io::Error::other)??;
+        // 只有表中和这里各持一份、没有其他任务持有或等待时才移除,
+        // 否则重建的文档会拿到新锁, 与旧锁上的写入并发
+        self.flush_locks
+            .remove_if(doc_id, |_, entry| Arc::ptr_eq(entry, &lock) && Arc::strong_count(entry) == 2);
+        tracing::info!(doc_id = %doc_id, "Document deleted");
+        Ok(())
+    }
+
+    /// 从存储恢复文档: 快照 + 之后的日志
+    async fn recover(&self, doc_id: &str) -> std::io::Result<Option<Document>> {
+        let store = self.store.clone();
+        let id = doc_id.to_string();
+        let (snapshot, log) = tokio::task::spawn_blocking(move || {
+            let Some(snapshot) = store.load(&id)? else {
+                // 首个快照总是先于日志写入, 只有日志说明删除中途崩溃; 无从得知访问控制列表, 不恢复
+                if !store.load_log(&id, 0)?.is_empty() {
+                    tracing::warn!(doc_id = %id, "Discarding log without a snapshot");
+                    store.delete(&id)?;
+                }
+                return Ok((None, Vec::new()));
+            };
+            let log = store.load_log(&id, snapshot.version)?;
+            Ok::<_, std::io::Error>((Some(snapshot), log))
+        })
+        .await
+        .map_err(std::io::Error::other)??;
+
+        let Some(snapshot) = snapshot else {
+            return Ok(None);
+        };
+        let mut doc = Document::from_snapshot(snapshot);
+        let replayed = log.len();
+        for entry in log {
+            doc.replay(entry).map_err(std::io::Error::other)?;
+        }
+        tracing::info!(
+            doc_id = %doc_id,
+            "Document recovered at version {} ({} log entries replayed)",
+            doc.version(), replayed
+        );
+        Ok(Some(doc))
+    }
+
+    /// 文档的全部历史编辑记录 (先写入尚未保存的编辑)
+    pub async fn document_history(&self, doc_id: &str) -> std::io::Result<Vec<LogEntry>> {
+        self.flush_document(doc_id).await?;
+        let store = self.store.clone();
+        let id = doc_id.to_string();
+        tokio::task::spawn_blocking(move || store.load_history(&id, u64::MAX))
+            .await
+            .map_err(std::io::Error::other)?
+    }
+
+    /// 重放历史得到文档在 `version` 时的状态; 该版本不存在
+    /// (超过当前版本, 或落在一次 CRDT 批量合并中间) 时返回 `None`
+    pub async fn document_at(&self, doc_id: &str, version: u64) -> std::io::Result<Option<Document>> {
+        let Some(kind) = self.documents
+            .get(doc_id)
+            .filter(|doc| version <= doc.version())
+            .map(|doc| doc.kind())
+        else {
+            return Ok(None);
+        };
+
+        self.flush_document(doc_id).await?;
+        let store = self.store.clone();
+        let id = doc_id.to_string();
+        let entries = tokio::task::spawn_blocking(move || store.load_history(&id, version))
+            .await
+            .map_err(std::io::Error::other)??;
+
+        let mut doc = Document::new(kind);
+        for entry in entries {
+            doc.replay(entry).map_err(std::io::Error::other)?;
+        }
+        Ok((doc.version() == version).then_some(doc))
+    }
+
+    /// 标记文档有新的编辑需要写入存储
+    pub fn mark_dirty(&self, doc_id: &str) {
+        self.dirty.insert(doc_id.to_string());
+        self.flush_notify.notify_one();
+    }
+
+    /// 把所有有未写入编辑的文档写入存储
+    pub async fn flush(&self) {
+        let doc_ids: Vec<String> = self.dirty.iter().map(|id| id.clone()).collect();
+        for doc_id in doc_ids {
+            if let Err(e) = self.flush_document(&doc_id).await {
+                tracing::error!(doc_id = %doc_id, "Failed to persist document: {}", e);
+            }
+        }
+    }
+
+    /// 把文档未写入的编辑写入存储
+    ///
+    /// 编辑记录先追加到日志; 需要快照时再保存快照, 并把其之前的日志归档为历史
+    pub async fn flush_document(&self, doc_id: &str) -> std::io::Result<()> {
+        // 持有写入锁直到写完, 并发调用者等待后再检查, 返回时之前的编辑都已写入
+        let lock = self.flush_lock(doc_id);
+        let _guard = lock.lock().await;
+        if self.dirty.remove(doc_id).is_none() {
+            return Ok(());
+        }
+        let Some((entries, snapshot, first_snapshot)) = self.documents
+            .get_mut(doc_id)
+            .map(|mut doc| {
+                let first_snapshot = doc.snapshot_version.is_none();
+                let (entries, snapshot) = doc.take_persist_batch(self.snapshot_interval);
+                (entries, snapshot, first_snapshot)
+            })
+        else {
+            return Ok(());
+        };
+
+        let store = self.store.clone();
+        let id = doc_id.to_string();
+        let result = tokio::task::spawn_blocking(move || {
+            // 没有快照时先写快照, 崩溃后不会只剩日志而丢失访问控制列表和元数据
+            if let Some(snapshot) = snapshot.as_ref().filter(|_| first_snapshot) {
+                store.save_snapshot(&id, snapshot)?;
+            }
+            if !entries.is_empty() {
+                store.append_ops(&id, &entries)?;
+            }
+            if let Some(snapshot) = snapshot {
+                if !first_snapshot {
+                    store.save_snapshot(&id, &snapshot)?;
+                }
+                store.truncate_log(&id, snapshot.version)?;
+            }
+            Ok(())
+        })
+        .await
+        .map_err(std::io::Error::other)
+        .and_then(|result| result);
+
+        if result.is_err() {
+            // 丢失的记录由下次写入的完整快照补上
+            if let Some(mut doc) = self.documents.get_mut(doc_id) {
+                doc.invalidate_snapshot();
+            }
+            self.dirty.insert(doc_id.to_string());
+        }
+        result
+    }
+
+    fn flush_lock(&self, doc_id: &str) -> Arc<Mutex<()>> {
+        self.flush_locks.entry(doc_id.to_string()).or_default().clone()
+    }
+
+    /// 后台写入任务, 按 `FlushPolicy` 把编辑写入存储
+    pub async fn run_flusher(self) {
+        loop {
+            self.flush_notify.notified().await;
+            if let FlushPolicy::Debounce(delay) = self.flush_policy {
+                tokio::time::sleep(delay).await;
+            }
+            self.flush().await;
+        }
+    }
+
+    /// 后台任务, 定期检查用户活动: 空闲超过 `away_after` 的标记为离开并广播,
+    /// 超过 `disconnect_after` 的断开连接
+    pub async fn run_reaper(self) {
+        let period = (self.idle_policy.away_after.min(self.idle_policy.disconnect_after) / 4)
+            .max(std::time::Duration::from_secs(1));
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            self.reap_idle_users();
+            self.sessions.retain(|_, session| session.expires_at > std::time::SystemTime::now());
+        }
+    }
+
+    fn reap_idle_users(&self) {
+        let now = std::time::SystemTime::now();
+        let mut away = Vec::new();
+        for mut user in self.users.iter_mut() {
+            let idle = now.duration_since(user.last_activity).unwrap_or_default();
+            if idle >= self.idle_policy.disconnect_after {
+                tracing::info!(doc_id = %user.doc_id, "Disconnecting user {} after {:?} idle", user.id, idle);
+                user.disconnect.notify_one();
+            } else if idle >= self.idle_policy.away_after && !user.away {
+                user.away = true;
+                away.push((user.doc_id.clone(), user.presence()));
+            }
+        }
+        // 释放用户表的锁后再广播
+        for (doc_id, presence) in away {
+            self.broadcast(&doc_id, ServerMessage::PresenceUpdate(presence));
+        }
+    }
+
+    /// 添加用户到其所在文档, 返回该文档当前的用户数
+    pub fn add_user(&self, user: User) -> usize {
+        let doc_id = user.doc_id.clone();
+
+        self.users.insert(user.connection_id.clone(), user);
+        self.get_user_count(&doc_id)
+    }
+
+    /// 移除连接并保留其会话以便恢复, 返回其所在文档剩余的用户数
+    pub fn remove_user(&self, connection_id: &str) -> Option<(String, usize)> {
+        let (_, user) = self.users.remove(connection_id)?;
+        let count = self.get_user_count(&user.doc_id);
+        self.sessions.insert(user.resume_token, Session {
+            user_id: user.id,
+            connection_id: user.connection_id,
+            doc_id: user.doc_id.clone(),
+            name: user.name,
+            color: user.color,
+            expires_at: std::time::SystemTime::now() + self.session_ttl,
+        });
+        Some((user.doc_id, count))
+    }
+
+    /// 取出可恢复的会话; 令牌只能使用一次, 过期时返回 `None`
+    ///
+    /// 属于其他文档或 (指定 `user_id` 时) 其他用户的会话返回 `None` 且保留, 不会被他人作废
+    pub fn take_session(&self, resume_token: &str, doc_id: &str, user_id: Option<&str>) -> Option<Session> {
+        let (_, session) = self.sessions.remove_if(resume_token, |_, session| {
+            session.doc_id == doc_id && user_id.is_none_or(|user_id| session.user_id == user_id)
+        })?;
+        (session.expires_at > std::time::SystemTime::now()).then_some(session)
+    }
+
+    pub fn get_user_count(&self, doc_id: &str) -> usize {
+        self.users.iter().filter(|user| user.doc_id == doc_id).count()
+    }
+
+    /// 文档中全部用户的状态, 按连接时间排序
+    pub fn document_presence(&self, doc_id: &str) -> Vec<UserPresence> {
+        let mut users: Vec<UserPresence> = self
+            .users
+            .iter()
+            .filter(|user| user.doc_id == doc_id)
+            .map(|user| user.presence())
+            .collect();
+        users.sort_by_key(|user| user.connected_at);
+        users
+    }
+
+    /// 订阅指定文档的广播通道, 通道不存在时创建
+    pub fn subscribe(&self, doc_id: &str) -> broadcast::Receiver<Arc<ServerMessage>> {
+        self.channels
+            .entry(doc_id.to_string())
+            .or_insert_with(|| broadcast::channel(self.channel_capacity).0)
+            .subscribe()
+    }
+
+    /// 文档已没有订阅者时释放其广播通道
+    pub fn release_channel(&self, doc_id: &str) {
+        if self.channels.remove_if(doc_id, |_, tx| tx.receiver_count() == 0).is_some() {
+            tracing::debug!(doc_id = %doc_id, "Broadcast channel released");
+        }
     }
 
-    pub fn remove_user(&self, user_id: &str) -> usize {
-        self.users.remove(user_id);
-        self.users.len()
+    /// 发送消息给指定文档中的所有连接
+    pub fn broadcast(&self, doc_id: &str, message: ServerMessage) {
+        if let Some(tx) = self.channels.get(doc_id) {
+            let _ = tx.send(Arc::new(message));
+        }
+    }
+
+    /// 记录连接的选区
+    pub fn set_selection(&self, connection_id: &str, selection: Selection) {
+        if let Some(mut user) = self.users.get_mut(connection_id) {
+            user.selection = Some(selection);
+        }
     }
 
-    pub fn get_user_count(&self) -> usize {
-        self.users.len()
+    /// 文档被编辑后, 把其中所有用户的选区转换到新版本
+    pub fn transform_selections(&self, doc_id: &str, op: &Operation) {
+        for mut user in self.users.iter_mut() {
+            if user.doc_id != doc_id {
+                continue;
+            }
+            if let Some(selection) = user.selection.as_mut() {
+                *selection = selection.transform(op);
+            }
+        }
     }
 
-    pub fn update_user_activity(&self, user_id: &str) {
-        if let Some(mut user) = self.users.get_mut(user_id) {
+    /// 记录连接的活动, 已标记为离开的用户恢复在线并广播
+    pub fn update_user_activity(&self, connection_id: &str) {
+        let back = {
+            let Some(mut user) = self.users.get_mut(connection_id) else {
+                return;
+            };
             user.last_activity = std::time::SystemTime::now();
+            let was_away = std::mem::replace(&mut user.away, false);
+            was_away.then(|| (user.doc_id.clone(), user.presence()))
+        };
+        if let Some((doc_id, presence)) = back {
+            self.broadcast(&doc_id, ServerMessage::PresenceUpdate(presence));
+        }
+    }
+
+    /// 记录连接的往返延迟; 首次测得或变化明显时广播, 同一连接按 `LATENCY_BROADCAST_INTERVAL` 限频
+    pub fn update_latency(&self, connection_id: &str, latency: std::time::Duration) {
+        let changed = {
+            let Some(mut user) = self.users.get_mut(connection_id) else {
+                return;
+            };
+            user.latency = Some(latency);
+            let due = user.latency_broadcast.is_none_or(|(last, at)| {
+                at.elapsed() >= LATENCY_BROADCAST_INTERVAL && last.abs_diff(latency) >= LATENCY_BROADCAST_THRESHOLD
+            });
+            due.then(|| {
+                user.latency_broadcast = Some((latency, std::time::Instant::now()));
+                (user.doc_id.clone(), user.presence())
+            })
+        };
+        if let Some((doc_id, presence)) = changed {
+            self.broadcast(&doc_id, ServerMessage::PresenceUpdate(presence));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{FileStore, RedbStore};
+
+    const AUTHOR: Author<'static> = Author { user_id: Some("alice"), connection_id: None };
+
+    fn temp_dir() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("realtime-editor-test-{}", uuid::Uuid::new_v4()))
+    }
+
+    fn new_state(store: &Arc<dyn DocumentStore>, snapshot_interval: u64) -> AppState {
+        let mut config = Config::default();
+        config.storage.snapshot_interval = snapshot_interval;
+        AppState::new(store.clone(), &config, None)
+    }
+
+    /// 逐个写入 `contents`, 每次编辑后都写入存储
+    async fn edit(state: &AppState, kind: DocumentKind, contents: &[&str]) {
+        state.load_document("doc", kind, None).await.unwrap();
+        for content in contents {
+            state.documents.get_mut("doc").unwrap().update(content, AUTHOR).unwrap();
+            state.mark_dirty("doc");
+            state.flush_document("doc").await.unwrap();
+        }
+    }
+
+    async fn recovered(store: &Arc<dyn DocumentStore>) -> (AppState, String, u64) {
+        let state = new_state(store, 3);
+        assert!(state.open_document("doc").await.unwrap());
+        let (content, version) = {
+            let doc = state.documents.get("doc").unwrap();
+            (doc.content().to_string(), doc.version())
+        };
+        (state, content, version)
+    }
+
+    async fn check_recovery(store: Arc<dyn DocumentStore>, kind: DocumentKind) {
+        // 每 3 个版本一次快照: 快照在版本 1 和 4, 版本 5 只在日志中
+        edit(&new_state(&store, 3), kind, &["a", "ab", "abc", "abcd", "abcde"]).await;
+
+        let (state, content, version) = recovered(&store).await;
+        assert_eq!((content.as_str(), version), ("abcde", 5));
+        for (version, content) in [(0, ""), (2, "ab"), (4, "abcd"), (5, "abcde")] {
+            let doc = state.document_at("doc", version).await.unwrap().unwrap();
+            assert_eq!((doc.content(), doc.version()), (content, version));
+        }
+        assert!(state.document_at("doc", 6).await.unwrap().is_none());
+
+        // 以旧版本的内容创建新版本
+        let old = state.document_at("doc", 2).await.unwrap().unwrap();
+        state.documents.get_mut("doc").unwrap().update(old.content(), AUTHOR).unwrap();
+        state.mark_dirty("doc");
+        state.flush_document("doc").await.unwrap();
+        let (_, content, version) = recovered(&store).await;
+        assert_eq!(content, "ab");
+        assert!(version > 5);
+    }
+
+    #[tokio::test]
+    async fn file_store_recovers_snapshot_and_log() {
+        for kind in [DocumentKind::Ot, DocumentKind::Crdt] {
+            check_recovery(Arc::new(FileStore::new(temp_dir()).unwrap()), kind).await;
         }
     }
 
-    pub fn get_user_last_activity(&self, user_id: &str) -> Option<std::time::SystemTime> {
-        self.users.get(user_id).map(|user| user.last_activity)
+    #[tokio::test]
+    async fn redb_store_recovers_snapshot_and_log() {
+        for kind in [DocumentKind::Ot, DocumentKind::Crdt] {
+            let store = RedbStore::open(temp_dir().join("documents.redb")).unwrap();
+            check_recovery(Arc::new(store), kind).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn recovery_ignores_torn_log_tail() {
+        use std::io::Write;
+
+        let dir = temp_dir();
+        let store: Arc<dyn DocumentStore> = Arc::new(FileStore::new(&dir).unwrap());
+        edit(&new_state(&store, 100), DocumentKind::Ot, &["a", "ab", "abc"]).await;
+
+        // 追加下一条记录时崩溃, 只写入了一部分
+        let mut log = std::fs::OpenOptions::new().append(true).open(dir.join("doc.log")).unwrap();
+        log.write_all(br#"{"version":4,"timestamp":0,"type":"ot","ops":[{"ret"#).unwrap();
+
+        let (_, content, version) = recovered(&store).await;
+        assert_eq!((content.as_str(), version), ("abc", 3));
     }
-}
\ No newline at end of file
+
+    /// 追加日志总是失败的存储, 模拟写完第一步后崩溃
+    #[derive(Debug)]
+    struct FailingLog(FileStore);
+
+    impl DocumentStore for FailingLog {
+        fn load(&self, doc_id: &str) -> std::io::Result<Option<DocumentSnapshot>> {
+            self.0.load(doc_id)
+        }
+        fn save_snapshot(&self, doc_id: &str, snapshot: &DocumentSnapshot) -> std::io::Result<()> {
+            self.0.save_snapshot(doc_id, snapshot)
+        }
+        fn append_ops(&self, _: &str, _: &[LogEntry]) -> std::io::Result<()> {
+            Err(std::io::Error::other("crashed"))
+        }
+        fn load_log(&self, doc_id: &str, after_version: u64) -> std::io::Result<Vec<LogEntry>> {
+            self.0.load_log(doc_id, after_version)
+        }
+        fn truncate_log(&self, doc_id: &str, up_to_version: u64) -> std::io::Result<()> {
+            self.0.truncate_log(doc_id, up_to_version)
+        }
+        fn load_history(&self, doc_id: &str, up_to_version: u64) -> std::io::Result<Vec<LogEntry>> {
+            self.0.load_history(doc_id, up_to_version)
+        }
+        fn list(&self) -> std::io::Result<Vec<String>> {
+            self.0.list()
+        }
+        fn delete(&self, doc_id: &str) -> std::io::Result<()> {
+            self.0.delete(doc_id)
+        }
+    }
+
+    #[tokio::test]
+    async fn first_snapshot_is_saved_before_the_log() {
+        let dir = temp_dir();
+        let failing: Arc<dyn DocumentStore> = Arc::new(FailingLog(FileStore::new(&dir).unwrap()));
+        let state = new_state(&failing, 100);
+        state.load_document("doc", DocumentKind::Ot, Some("alice")).await.unwrap();
+        state.documents.get_mut("doc").unwrap().update("secret", AUTHOR).unwrap();
+        state.mark_dirty("doc");
+        assert!(state.flush_document("doc").await.is_err());
+
+        // 日志写入前已有快照, 恢复后仍只有所有者可以访问
+        let store: Arc<dyn DocumentStore> = Arc::new(FileStore::new(&dir).unwrap());
+        let (state, _, _) = recovered(&store).await;
+        let doc = state.documents.get("doc").unwrap();
+        assert_eq!(doc.role("alice"), Some(Role::Owner));
+        assert_eq!(doc.role("bob"), None);
+    }
+
+    #[tokio::test]
+    async fn log_without_snapshot_is_discarded() {
+        let store: Arc<dyn DocumentStore> = Arc::new(FileStore::new(temp_dir()).unwrap());
+        let mut ops = Operation::new();
+        ops.insert("secret");
+        store.append_ops("doc", &[LogEntry::new(1, LoggedOp::Ot { ops }, None)]).unwrap();
+
+        let state = new_state(&store, 100);
+        assert!(!state.open_document("doc").await.unwrap());
+        assert!(store.load_log("doc", 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn session_is_only_taken_by_its_owner() {
+        let store: Arc<dyn DocumentStore> = Arc::new(FileStore::new(temp_dir()).unwrap());
+        let state = new_state(&store, 100);
+        state.sessions.insert("token".to_string(), Session {
+            user_id: "alice".to_string(),
+            connection_id: "connection".to_string(),
+            doc_id: "doc".to_string(),
+            name: "Alice".to_string(),
+            color: USER_COLORS[0].to_string(),
+            expires_at: std::time::SystemTime::now() + std::time::Duration::from_secs(60),
+        });
+
+        // 其他文档或其他用户不能使用, 也不会使令牌作废
+        assert!(state.take_session("token", "other", None).is_none());
+        assert!(state.take_session("token", "doc", Some("bob")).is_none());
+        let session = state.take_session("token", "doc", Some("alice")).unwrap();
+        assert_eq!(session.connection_id, "connection");
+        assert!(state.take_session("token", "doc", Some("alice")).is_none());
+    }
+}
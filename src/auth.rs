@@ -0,0 +1,109 @@
+use std::fmt;
+
+use axum::http::{header, HeaderMap};
+use jsonwebtoken::{errors::Error, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+
+/// 令牌中的身份信息
+#[derive(Debug, Clone, Deserialize)]
+pub struct Claims {
+    /// SSO 中的用户ID, 作为稳定的用户身份
+    pub sub: String,
+    /// 显示名称, 优先于连接参数中的名称
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+/// 校验连接令牌 (JWT), 支持 HMAC (HS256) 或 EdDSA (Ed25519) 签名, 要求带有 `exp`
+#[derive(Clone)]
+pub struct Authenticator {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl fmt::Debug for Authenticator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Authenticator")
+            .field("algorithms", &self.validation.algorithms)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Authenticator {
+    pub fn hmac(secret: &[u8]) -> Self {
+        Self {
+            key: DecodingKey::from_secret(secret),
+            validation: Validation::new(Algorithm::HS256),
+        }
+    }
+
+    /// 使用 PEM 格式的 Ed25519 公钥
+    pub fn ed25519(public_key_pem: &[u8]) -> Result<Self, Error> {
+        Ok(Self {
+            key: DecodingKey::from_ed_pem(public_key_pem)?,
+            validation: Validation::new(Algorithm::EdDSA),
+        })
+    }
+
+    /// 要求令牌的 `aud` 包含 `audience`、`iss` 等于 `issuer`; 未配置受众时不检查 `aud`
+    pub fn expect(mut self, audience: Option<&str>, issuer: Option<&str>) -> Self {
+        match audience {
+            Some(audience) => {
+                self.validation.set_audience(&[audience]);
+                self.validation.required_spec_claims.insert("aud".to_string());
+            }
+            None => self.validation.validate_aud = false,
+        }
+        if let Some(issuer) = issuer {
+            self.validation.set_issuer(&[issuer]);
+            self.validation.required_spec_claims.insert("iss".to_string());
+        }
+        self
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims, Error> {
+        jsonwebtoken::decode::<Claims>(token, &self.key, &self.validation).map(|data| data.claims)
+    }
+}
+
+/// 从 `Authorization: Bearer` 头或 `token` 查询参数中取出令牌, 浏览器的 WebSocket 无法设置请求头
+pub fn bearer_token<'a>(headers: &'a HeaderMap, query_token: Option<&'a str>) -> Option<&'a str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or(query_token)
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    const SECRET: &[u8] = b"secret";
+
+    fn token(mut claims: Value) -> String {
+        claims["sub"] = json!("alice");
+        claims["exp"] = json!(jsonwebtoken::get_current_timestamp() + 600);
+        encode(&Header::default(), &claims, &EncodingKey::from_secret(SECRET)).unwrap()
+    }
+
+    #[test]
+    fn accepts_audience_when_not_configured() {
+        let auth = Authenticator::hmac(SECRET).expect(None, None);
+        assert!(auth.verify(&token(json!({}))).is_ok());
+        assert!(auth.verify(&token(json!({ "aud": "other", "iss": "other" }))).is_ok());
+    }
+
+    #[test]
+    fn checks_configured_audience_and_issuer() {
+        let auth = Authenticator::hmac(SECRET).expect(Some("editor"), Some("sso"));
+        assert!(auth.verify(&token(json!({ "aud": ["editor", "wiki"], "iss": "sso" }))).is_ok());
+        assert!(auth.verify(&token(json!({ "aud": "wiki", "iss": "sso" }))).is_err());
+        assert!(auth.verify(&token(json!({ "aud": "editor", "iss": "other" }))).is_err());
+        assert!(auth.verify(&token(json!({ "iss": "sso" }))).is_err());
+        assert!(auth.verify(&token(json!({ "aud": "editor" }))).is_err());
+    }
+}
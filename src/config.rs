@@ -0,0 +1,591 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use clap::{Parser, ValueEnum};
+use serde::Deserialize;
+
+use crate::app::{FlushPolicy, IdlePolicy};
+use crate::auth::Authenticator;
+
+/// 未通过 `--config` 指定时, 当前目录下存在此文件则读取
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+/// 环境变量名的前缀, 如 `REALTIME_EDITOR_SERVER_PORT`
+const ENV_PREFIX: &str = "REALTIME_EDITOR_";
+
+/// 命令行参数, 优先级最高
+#[derive(Debug, Parser)]
+#[command(about = "Realtime collaborative editor server")]
+pub struct Cli {
+    /// TOML 配置文件路径
+    #[arg(long, short)]
+    config: Option<PathBuf>,
+    /// 监听地址, 如 0.0.0.0
+    #[arg(long)]
+    bind: Option<String>,
+    /// 监听端口
+    #[arg(long)]
+    port: Option<u16>,
+    /// trace / debug / info / warn / error
+    #[arg(long)]
+    log_level: Option<String>,
+    /// 存储后端
+    #[arg(long)]
+    storage_backend: Option<StorageBackend>,
+    /// 数据目录
+    #[arg(long)]
+    storage_path: Option<PathBuf>,
+    /// 每个文档广播通道的容量
+    #[arg(long)]
+    broadcast_capacity: Option<usize>,
+    /// TLS 证书 (PEM) 路径
+    #[arg(long)]
+    tls_cert: Option<PathBuf>,
+    /// TLS 私钥 (PEM) 路径
+    #[arg(long)]
+    tls_key: Option<PathBuf>,
+    /// 使用启动时生成的自签名证书, 仅用于本地开发
+    #[arg(long)]
+    tls_self_signed: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    #[default]
+    File,
+    Redb,
+}
+
+/// 服务器配置: 默认值 < 配置文件 < 环境变量 < 命令行参数
+///
+/// 环境变量名为 `ENV_PREFIX` 加 `{段}_{键}` 的大写形式, 如 `REALTIME_EDITOR_SERVER_PORT`、
+/// `REALTIME_EDITOR_STORAGE_PATH`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub storage: StorageConfig,
+    pub flush: FlushConfig,
+    pub idle: IdleConfig,
+    pub auth: AuthConfig,
+    pub connection: ConnectionConfig,
+    pub rate_limit: RateLimitConfig,
+    pub limits: LimitsConfig,
+    pub tls: TlsConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: String,
+    pub port: u16,
+    pub log_level: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: "127.0.0.1".to_string(),
+            port: 8080,
+            log_level: "info".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    pub path: PathBuf,
+    /// 每隔多少个版本保存一次快照并截断日志, 内存中也只保留这么多个最近的 OT 操作
+    pub snapshot_interval: u64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            backend: StorageBackend::File,
+            path: PathBuf::from("data"),
+            snapshot_interval: 100,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FlushConfig {
+    /// 首次编辑后等待多久写入存储, 0 表示每次编辑后立即写入
+    ///
+    /// 编辑先广播再写入, 写入时同步到磁盘; 崩溃时最多丢失这段时间内已广播的编辑
+    pub debounce_ms: u64,
+}
+
+impl Default for FlushConfig {
+    fn default() -> Self {
+        Self { debounce_ms: 500 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdleConfig {
+    pub away_secs: u64,
+    pub disconnect_secs: u64,
+}
+
+impl Default for IdleConfig {
+    fn default() -> Self {
+        let policy = IdlePolicy::default();
+        Self {
+            away_secs: policy.away_after.as_secs(),
+            disconnect_secs: policy.disconnect_after.as_secs(),
+        }
+    }
+}
+
+/// 二者都未配置时不要求认证
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// HS256 密钥
+    pub hmac_secret: Option<String>,
+    /// Ed25519 公钥 (PEM) 文件路径
+    pub ed25519_public_key: Option<PathBuf>,
+    /// 令牌的 `aud` 必须包含的受众, 未配置时不检查
+    pub audience: Option<String>,
+    /// 令牌的 `iss` 必须等于的签发者, 未配置时不检查
+    pub issuer: Option<String>,
+}
+
+/// 未配置证书时以明文 HTTP 提供服务, 由反向代理终止 TLS
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// 证书链 (PEM) 文件路径, 收到 SIGHUP 时重新读取
+    pub cert: Option<PathBuf>,
+    /// 私钥 (PEM) 文件路径
+    pub key: Option<PathBuf>,
+    /// 启动时生成自签名证书
+    pub self_signed: bool,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionConfig {
+    /// 每个文档广播通道的容量
+    pub broadcast_capacity: usize,
+    /// 连接建立后发送首个快照的超时
+    pub test_timeout_ms: u64,
+    pub heartbeat_interval_secs: u64,
+    /// 连续这么多次 ping 没有收到 pong 时视为连接已断开
+    pub max_missed_pongs: u32,
+    /// 会话断开后可恢复的时间
+    pub session_ttl_secs: u64,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            broadcast_capacity: 1000,
+            test_timeout_ms: 100,
+            heartbeat_interval_secs: 15,
+            max_missed_pongs: 3,
+            session_ttl_secs: 120,
+        }
+    }
+}
+
+impl ConnectionConfig {
+    pub fn test_timeout(&self) -> Duration {
+        Duration::from_millis(self.test_timeout_ms)
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_interval_secs)
+    }
+
+    pub fn session_ttl(&self) -> Duration {
+        Duration::from_secs(self.session_ttl_secs)
+    }
+}
+
+/// 每个连接的消息限流, 速率为 0 表示不限制该项
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub messages_per_sec: u64,
+    /// 允许短时间内突发的消息数
+    pub message_burst: u64,
+    pub bytes_per_sec: u64,
+    /// 允许突发的字节数, 不能小于单条消息的上限
+    pub byte_burst: u64,
+    /// 窗口内被拒绝的消息超过此数时断开连接
+    pub max_violations: u32,
+    pub violation_window_secs: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            messages_per_sec: 20,
+            message_burst: 60,
+            bytes_per_sec: 1024 * 1024,
+            byte_burst: 4 * 1024 * 1024,
+            max_violations: 20,
+            violation_window_secs: 10,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn violation_window(&self) -> Duration {
+        Duration::from_secs(self.violation_window_secs)
+    }
+}
+
+/// 消息与文档的大小上限 (字节)
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// 单条 WebSocket 消息或 REST 请求体
+    pub max_message_bytes: usize,
+    /// 文档内容 (UTF-8)
+    pub max_document_bytes: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_message_bytes: 1024 * 1024,
+            max_document_bytes: 10 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read { path: PathBuf, source: std::io::Error },
+    Parse { path: PathBuf, source: toml::de::Error },
+    Env { name: String, value: String, reason: String },
+    Invalid { key: &'static str, reason: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, source } => {
+                write!(f, "cannot read config file {}: {}", path.display(), source)
+            }
+            ConfigError::Parse { path, source } => {
+                write!(f, "invalid config file {}: {}", path.display(), source)
+            }
+            ConfigError::Env { name, value, reason } => {
+                write!(f, "invalid environment variable {}={:?}: {}", name, value, reason)
+            }
+            ConfigError::Invalid { key, reason } => write!(f, "invalid `{}`: {}", key, reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// 按优先级合并配置文件、环境变量和命令行参数, 并校验
+    pub fn load(cli: Cli) -> Result<Self, ConfigError> {
+        let mut config = match &cli.config {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => Self::from_file(Path::new(DEFAULT_CONFIG_FILE))?,
+            None => Self::default(),
+        };
+        config.apply_env()?;
+        config.apply_cli(cli);
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|source| ConfigError::Read { path: path.to_path_buf(), source })?;
+        toml::from_str(&text).map_err(|source| ConfigError::Parse { path: path.to_path_buf(), source })
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        env("SERVER_BIND", &mut self.server.bind)?;
+        env("SERVER_PORT", &mut self.server.port)?;
+        env("SERVER_LOG_LEVEL", &mut self.server.log_level)?;
+        env_with("STORAGE_BACKEND", &mut self.storage.backend, |value| StorageBackend::from_str(value, true))?;
+        env("STORAGE_PATH", &mut self.storage.path)?;
+        env("STORAGE_SNAPSHOT_INTERVAL", &mut self.storage.snapshot_interval)?;
+        env("FLUSH_DEBOUNCE_MS", &mut self.flush.debounce_ms)?;
+        env("IDLE_AWAY_SECS", &mut self.idle.away_secs)?;
+        env("IDLE_DISCONNECT_SECS", &mut self.idle.disconnect_secs)?;
+        env_opt("AUTH_HMAC_SECRET", &mut self.auth.hmac_secret)?;
+        env_opt("AUTH_ED25519_PUBLIC_KEY", &mut self.auth.ed25519_public_key)?;
+        env_opt("AUTH_AUDIENCE", &mut self.auth.audience)?;
+        env_opt("AUTH_ISSUER", &mut self.auth.issuer)?;
+        env("CONNECTION_BROADCAST_CAPACITY", &mut self.connection.broadcast_capacity)?;
+        env("CONNECTION_TEST_TIMEOUT_MS", &mut self.connection.test_timeout_ms)?;
+        env("CONNECTION_HEARTBEAT_INTERVAL_SECS", &mut self.connection.heartbeat_interval_secs)?;
+        env("CONNECTION_MAX_MISSED_PONGS", &mut self.connection.max_missed_pongs)?;
+        env("CONNECTION_SESSION_TTL_SECS", &mut self.connection.session_ttl_secs)?;
+        env("RATE_LIMIT_MESSAGES_PER_SEC", &mut self.rate_limit.messages_per_sec)?;
+        env("RATE_LIMIT_MESSAGE_BURST", &mut self.rate_limit.message_burst)?;
+        env("RATE_LIMIT_BYTES_PER_SEC", &mut self.rate_limit.bytes_per_sec)?;
+        env("RATE_LIMIT_BYTE_BURST", &mut self.rate_limit.byte_burst)?;
+        env("RATE_LIMIT_MAX_VIOLATIONS", &mut self.rate_limit.max_violations)?;
+        env("RATE_LIMIT_VIOLATION_WINDOW_SECS", &mut self.rate_limit.violation_window_secs)?;
+        env("LIMITS_MAX_MESSAGE_BYTES", &mut self.limits.max_message_bytes)?;
+        env("LIMITS_MAX_DOCUMENT_BYTES", &mut self.limits.max_document_bytes)?;
+        env_opt("TLS_CERT", &mut self.tls.cert)?;
+        env_opt("TLS_KEY", &mut self.tls.key)?;
+        env("TLS_SELF_SIGNED", &mut self.tls.self_signed)?;
+        Ok(())
+    }
+
+    fn apply_cli(&mut self, cli: Cli) {
+        if let Some(bind) = cli.bind {
+            self.server.bind = bind;
+        }
+        if let Some(port) = cli.port {
+            self.server.port = port;
+        }
+        if let Some(log_level) = cli.log_level {
+            self.server.log_level = log_level;
+        }
+        if let Some(backend) = cli.storage_backend {
+            self.storage.backend = backend;
+        }
+        if let Some(path) = cli.storage_path {
+            self.storage.path = path;
+        }
+        if let Some(capacity) = cli.broadcast_capacity {
+            self.connection.broadcast_capacity = capacity;
+        }
+        if let Some(cert) = cli.tls_cert {
+            self.tls.cert = Some(cert);
+        }
+        if let Some(key) = cli.tls_key {
+            self.tls.key = Some(key);
+        }
+        if cli.tls_self_signed {
+            self.tls.self_signed = true;
+        }
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |key, reason: &str| Err(ConfigError::Invalid { key, reason: reason.to_string() });
+
+        if self.server.bind.parse::<IpAddr>().is_err() {
+            return invalid("server.bind", "must be an IP address such as 127.0.0.1 or 0.0.0.0");
+        }
+        if self.log_level().is_err() {
+            return invalid("server.log_level", "must be one of trace, debug, info, warn, error");
+        }
+        if self.storage.snapshot_interval == 0 {
+            return invalid("storage.snapshot_interval", "must be at least 1");
+        }
+        if self.idle.away_secs == 0 || self.idle.disconnect_secs == 0 {
+            return invalid("idle", "away_secs and disconnect_secs must be at least 1");
+        }
+        if self.auth.hmac_secret.is_some() && self.auth.ed25519_public_key.is_some() {
+            return invalid("auth", "configure either hmac_secret or ed25519_public_key, not both");
+        }
+        let has_key = self.auth.hmac_secret.is_some() || self.auth.ed25519_public_key.is_some();
+        if !has_key && (self.auth.audience.is_some() || self.auth.issuer.is_some()) {
+            return invalid("auth", "audience and issuer require hmac_secret or ed25519_public_key");
+        }
+        if self.auth.hmac_secret.as_deref().is_some_and(str::is_empty) {
+            return invalid("auth.hmac_secret", "must not be empty");
+        }
+        if self.connection.broadcast_capacity == 0 {
+            return invalid("connection.broadcast_capacity", "must be at least 1");
+        }
+        if self.connection.test_timeout_ms == 0 {
+            return invalid("connection.test_timeout_ms", "must be at least 1");
+        }
+        if self.connection.heartbeat_interval_secs == 0 {
+            return invalid("connection.heartbeat_interval_secs", "must be at least 1");
+        }
+        if self.connection.max_missed_pongs == 0 {
+            return invalid("connection.max_missed_pongs", "must be at least 1");
+        }
+        if self.rate_limit.messages_per_sec > 0 && self.rate_limit.message_burst == 0 {
+            return invalid("rate_limit.message_burst", "must be at least 1 when messages_per_sec is set");
+        }
+        if self.rate_limit.bytes_per_sec > 0 && self.rate_limit.byte_burst == 0 {
+            return invalid("rate_limit.byte_burst", "must be at least 1 when bytes_per_sec is set");
+        }
+        if self.rate_limit.violation_window_secs == 0 {
+            return invalid("rate_limit.violation_window_secs", "must be at least 1");
+        }
+        if self.rate_limit.bytes_per_sec > 0 && self.rate_limit.byte_burst < self.limits.max_message_bytes as u64 {
+            return invalid("rate_limit.byte_burst", "must not be smaller than limits.max_message_bytes");
+        }
+        if self.limits.max_message_bytes == 0 || self.limits.max_document_bytes == 0 {
+            return invalid("limits", "max_message_bytes and max_document_bytes must be at least 1");
+        }
+        if self.tls.cert.is_some() != self.tls.key.is_some() {
+            return invalid("tls", "cert and key must be configured together");
+        }
+        if self.tls.self_signed && self.tls.cert.is_some() {
+            return invalid("tls.self_signed", "cannot be combined with cert and key");
+        }
+        Ok(())
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        // validate 已确保地址合法
+        SocketAddr::new(self.server.bind.parse().expect("validated bind address"), self.server.port)
+    }
+
+    pub fn log_level(&self) -> Result<tracing::Level, tracing::metadata::ParseLevelError> {
+        self.server.log_level.parse()
+    }
+
+    pub fn flush_policy(&self) -> FlushPolicy {
+        match self.flush.debounce_ms {
+            0 => FlushPolicy::OnUpdate,
+            ms => FlushPolicy::Debounce(Duration::from_millis(ms)),
+        }
+    }
+
+    pub fn idle_policy(&self) -> IdlePolicy {
+        IdlePolicy {
+            away_after: Duration::from_secs(self.idle.away_secs),
+            disconnect_after: Duration::from_secs(self.idle.disconnect_secs),
+        }
+    }
+
+    /// 按配置创建令牌校验器, 未配置密钥时返回 `None`
+    pub fn authenticator(&self) -> Result<Option<Authenticator>, ConfigError> {
+        let authenticator = match (&self.auth.hmac_secret, &self.auth.ed25519_public_key) {
+            (Some(secret), _) => Authenticator::hmac(secret.as_bytes()),
+            (None, Some(path)) => {
+                let pem = std::fs::read(path).map_err(|source| ConfigError::Read { path: path.clone(), source })?;
+                Authenticator::ed25519(&pem)
+                    .map_err(|e| ConfigError::Invalid { key: "auth.ed25519_public_key", reason: e.to_string() })?
+            }
+            (None, None) => return Ok(None),
+        };
+        Ok(Some(authenticator.expect(self.auth.audience.as_deref(), self.auth.issuer.as_deref())))
+    }
+}
+
+/// 环境变量 `ENV_PREFIX` + `key` 存在时解析并覆盖 `target`
+fn env<T>(key: &str, target: &mut T) -> Result<(), ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    env_with(key, target, |value| value.parse().map_err(|e: T::Err| e.to_string()))
+}
+
+fn env_opt<T>(key: &str, target: &mut Option<T>) -> Result<(), ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    if let Some(value) = parse_env(key, |value| value.parse().map_err(|e: T::Err| e.to_string()))? {
+        *target = Some(value);
+    }
+    Ok(())
+}
+
+fn env_with<T>(key: &str, target: &mut T, parse: impl FnOnce(&str) -> Result<T, String>) -> Result<(), ConfigError> {
+    if let Some(value) = parse_env(key, parse)? {
+        *target = value;
+    }
+    Ok(())
+}
+
+fn parse_env<T>(key: &str, parse: impl FnOnce(&str) -> Result<T, String>) -> Result<Option<T>, ConfigError> {
+    let name = format!("{}{}", ENV_PREFIX, key);
+    let Ok(value) = std::env::var(&name) else {
+        return Ok(None);
+    };
+    parse(&value)
+        .map(Some)
+        .map_err(|reason| ConfigError::Env { name, value, reason })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invalid(change: impl FnOnce(&mut Config)) -> String {
+        let mut config = Config::default();
+        change(&mut config);
+        config.validate().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn validate_reports_the_offending_key() {
+        assert!(Config::default().validate().is_ok());
+        assert_eq!(
+            invalid(|c| c.server.bind = "localhost".to_string()),
+            "invalid `server.bind`: must be an IP address such as 127.0.0.1 or 0.0.0.0"
+        );
+        assert_eq!(
+            invalid(|c| c.storage.snapshot_interval = 0),
+            "invalid `storage.snapshot_interval`: must be at least 1"
+        );
+        assert_eq!(
+            invalid(|c| c.auth.audience = Some("editor".to_string())),
+            "invalid `auth`: audience and issuer require hmac_secret or ed25519_public_key"
+        );
+        assert_eq!(
+            invalid(|c| c.rate_limit.byte_burst = c.limits.max_message_bytes as u64 - 1),
+            "invalid `rate_limit.byte_burst`: must not be smaller than limits.max_message_bytes"
+        );
+        assert_eq!(
+            invalid(|c| c.tls.cert = Some(PathBuf::from("cert.pem"))),
+            "invalid `tls`: cert and key must be configured together"
+        );
+    }
+
+    // 环境变量是进程全局的, 只有这一个测试读写带前缀的变量, 不会与其他测试竞争
+    #[test]
+    fn precedence_and_environment_overrides() {
+        let path = std::env::temp_dir().join(format!("realtime-editor-test-{}.toml", uuid::Uuid::new_v4()));
+        let file = "[server]\nport = 7000\nlog_level = \"debug\"\n[storage]\nsnapshot_interval = 5\n";
+        std::fs::write(&path, file).unwrap();
+        let cli = |args: &[&str]| {
+            Cli::parse_from([&["realtime-editor", "--config", path.to_str().unwrap()], args].concat())
+        };
+        let set = |key: &str, value: &str| unsafe { std::env::set_var(format!("{}{}", ENV_PREFIX, key), value) };
+        let unset = |key: &str| unsafe { std::env::remove_var(format!("{}{}", ENV_PREFIX, key)) };
+
+        set("SERVER_PORT", "8000");
+        set("STORAGE_BACKEND", "REDB");
+        let config = Config::load(cli(&["--port", "9000"])).unwrap();
+        assert_eq!(config.server.port, 9000);
+        assert_eq!(config.storage.backend, StorageBackend::Redb);
+        assert_eq!(config.server.log_level, "debug");
+        assert_eq!(config.storage.snapshot_interval, 5);
+        assert_eq!(config.server.bind, ServerConfig::default().bind);
+        assert_eq!(Config::load(cli(&[])).unwrap().server.port, 8000);
+
+        set("SERVER_PORT", "eighty");
+        let error = Config::load(cli(&[])).unwrap_err();
+        assert!(matches!(&error, ConfigError::Env { name, value, .. }
+            if name == "REALTIME_EDITOR_SERVER_PORT" && value == "eighty"));
+        unset("SERVER_PORT");
+
+        set("STORAGE_BACKEND", "sqlite");
+        let error = Config::load(cli(&[])).unwrap_err();
+        assert!(matches!(&error, ConfigError::Env { name, .. } if name == "REALTIME_EDITOR_STORAGE_BACKEND"));
+        unset("STORAGE_BACKEND");
+
+        // 环境变量的值仍要通过校验
+        set("STORAGE_SNAPSHOT_INTERVAL", "0");
+        let error = Config::load(cli(&[])).unwrap_err();
+        assert!(matches!(error, ConfigError::Invalid { key: "storage.snapshot_interval", .. }));
+        unset("STORAGE_SNAPSHOT_INTERVAL");
+
+        assert_eq!(Config::load(cli(&[])).unwrap().server.port, 7000);
+    }
+}
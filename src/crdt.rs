@@ -0,0 +1,421 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::ot::{Component, Operation};
+
+/// 远端插入的时钟最多领先本地时钟的量, 也是时钟上限与 `u64::MAX` 的距离
+const MAX_CLOCK_STEP: u64 = 1 << 32;
+
+/// 字符的全局唯一标识: Lamport 时钟 + 站点ID
+///
+/// 排序先比较时钟再比较站点, 较大的标识在并发插入时排在前面
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct CharId {
+    pub clock: u64,
+    pub site: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CrdtOp {
+    /// 在 `after` 之后插入字符, `after` 为空表示插入到开头
+    Insert { id: CharId, after: Option<CharId>, value: char },
+    /// 删除字符 (保留墓碑)
+    Delete { id: CharId },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Element {
+    pub id: CharId,
+    pub value: char,
+    pub deleted: bool,
+}
+
+/// RGA 序列 CRDT: 各站点的操作无需服务器转换即可按任意顺序合并, 结果一致
+///
+/// 字符按文档顺序组成以标识索引的链表, 定位插入位置和删除都无需扫描整个文档
+#[derive(Debug, Clone)]
+pub struct Rga {
+    // 本站点ID, 用于服务器自身生成的操作
+    site: String,
+    clock: u64,
+    head: Option<CharId>,
+    nodes: HashMap<CharId, Node>,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    value: char,
+    deleted: bool,
+    next: Option<CharId>,
+}
+
+impl Rga {
+    pub fn new(site: &str) -> Self {
+        Self {
+            site: site.to_string(),
+            clock: 0,
+            head: None,
+            nodes: HashMap::new(),
+        }
+    }
+
+    /// 从快照元素恢复, 时钟取已有元素的最大值
+    pub fn from_elements(site: &str, elements: Vec<Element>) -> Self {
+        let clock = elements.iter().map(|e| e.id.clock).max().unwrap_or(0);
+        let mut nodes = HashMap::with_capacity(elements.len());
+        let mut next = None;
+        for element in elements.into_iter().rev() {
+            let node = Node { value: element.value, deleted: element.deleted, next: next.take() };
+            nodes.insert(element.id.clone(), node);
+            next = Some(element.id);
+        }
+        Self {
+            site: site.to_string(),
+            clock,
+            head: next,
+            nodes,
+        }
+    }
+
+    /// 按文档顺序的全部元素 (含墓碑)
+    pub fn elements(&self) -> Vec<Element> {
+        self.iter()
+            .map(|(id, node)| Element { id: id.clone(), value: node.value, deleted: node.deleted })
+            .collect()
+    }
+
+    /// 可见文本 (不含已删除的字符)
+    pub fn text(&self) -> String {
+        self.iter()
+            .filter(|(_, node)| !node.deleted)
+            .map(|(_, node)| node.value)
+            .collect()
+    }
+
+    /// 合并远端操作, 返回本次实际生效的操作数
+    ///
+    /// 批内操作可以乱序; 依赖的字符既不在文档中也不在本批插入中、批内插入互相依赖成环,
+    /// 或插入的时钟超出范围时整批拒绝, 文档不变
+    pub fn merge(&mut self, ops: Vec<CrdtOp>) -> Result<usize, CrdtError> {
+        let limit = self.clock.saturating_add(MAX_CLOCK_STEP).min(u64::MAX - MAX_CLOCK_STEP);
+        let inserted: HashSet<&CharId> = ops
+            .iter()
+            .filter_map(|op| match op {
+                CrdtOp::Insert { id, .. } => Some(id),
+                CrdtOp::Delete { .. } => None,
+            })
+            .collect();
+        if let Some(id) = inserted.iter().find(|id| id.clock > limit) {
+            return Err(CrdtError::ClockOutOfRange { clock: id.clock, current: self.clock });
+        }
+        for op in &ops {
+            let dependency = match op {
+                CrdtOp::Insert { after, .. } => after.as_ref(),
+                CrdtOp::Delete { id } => Some(id),
+            };
+            if let Some(id) = dependency.filter(|id| !self.nodes.contains_key(id) && !inserted.contains(id)) {
+                return Err(CrdtError::MissingDependency(id.clone()));
+            }
+        }
+
+        // 按依赖排序: 插入排在它所依赖的插入之后, 删除排在全部插入之后
+        let mut waiting: HashMap<CharId, Vec<CrdtOp>> = HashMap::new();
+        let mut ready = VecDeque::new();
+        let mut deletes = Vec::new();
+        for op in ops {
+            match &op {
+                CrdtOp::Insert { id, after: Some(after), .. }
+                    if !self.nodes.contains_key(id) && !self.nodes.contains_key(after) =>
+                {
+                    waiting.entry(after.clone()).or_default().push(op);
+                }
+                CrdtOp::Insert { .. } => ready.push_back(op),
+                CrdtOp::Delete { .. } => deletes.push(op),
+            }
+        }
+        let mut ordered = Vec::new();
+        while let Some(op) = ready.pop_front() {
+            if let CrdtOp::Insert { id, .. } = &op {
+                ready.extend(waiting.remove(id).unwrap_or_default());
+            }
+            ordered.push(op);
+        }
+        // 依赖都在本批中却无法排序, 说明依赖成环
+        if let Some(id) = waiting.into_values().flatten().find_map(|op| match op {
+            CrdtOp::Insert { id, .. } => Some(id),
+            CrdtOp::Delete { .. } => None,
+        }) {
+            return Err(CrdtError::Cycle(id));
+        }
+
+        let mut applied = 0;
+        for op in ordered.iter().chain(&deletes) {
+            // 重复的操作直接丢弃
+            if self.integrate(op) == Some(true) {
+                applied += 1;
+            }
+        }
+        Ok(applied)
+    }
+
+    /// 以本站点身份把可见文本替换为 `content`, 返回生成的操作
+    ///
+    /// 时钟耗尽时返回 `CrdtError::ClockExhausted`, 文档不变
+    pub fn replace(&mut self, content: &str) -> Result<Vec<CrdtOp>, CrdtError> {
+        let visible: Vec<CharId> = self
+            .iter()
+            .filter(|(_, node)| !node.deleted)
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        let mut ops = Vec::new();
+        let mut clock = self.clock;
+        let mut index = 0;
+        for component in Operation::diff(&self.text(), content).components() {
+            match component {
+                Component::Retain(n) => index += n,
+                Component::Insert(s) => {
+                    let mut after = index.checked_sub(1).map(|i| visible[i].clone());
+                    for value in s.chars() {
+                        clock = clock.checked_add(1).ok_or(CrdtError::ClockExhausted)?;
+                        let id = CharId { clock, site: self.site.clone() };
+                        ops.push(CrdtOp::Insert { id: id.clone(), after, value });
+                        after = Some(id);
+                    }
+                }
+                Component::Delete(n) => {
+                    for id in &visible[index..index + n] {
+                        ops.push(CrdtOp::Delete { id: id.clone() });
+                    }
+                    index += n;
+                }
+            }
+        }
+
+        for op in &ops {
+            self.integrate(op);
+        }
+        Ok(ops)
+    }
+
+    /// 合并单个操作: `Some(true)` 已生效, `Some(false)` 重复, `None` 依赖缺失
+    fn integrate(&mut self, op: &CrdtOp) -> Option<bool> {
+        match op {
+            CrdtOp::Insert { id, after, value } => {
+                if self.nodes.contains_key(id) {
+                    return Some(false);
+                }
+                let mut prev = match after {
+                    Some(after) if !self.nodes.contains_key(after) => return None,
+                    after => after.clone(),
+                };
+                let mut next = match &prev {
+                    Some(prev) => self.nodes[prev].next.clone(),
+                    None => self.head.clone(),
+                };
+                // 跳过标识更大的并发插入, 保证各站点顺序一致
+                while let Some(candidate) = next.take_if(|candidate| *candidate > *id) {
+                    next = self.nodes[&candidate].next.clone();
+                    prev = Some(candidate);
+                }
+                self.clock = self.clock.max(id.clock);
+                self.nodes.insert(id.clone(), Node { value: *value, deleted: false, next });
+                match prev {
+                    Some(prev) => self.nodes.get_mut(&prev).expect("node exists").next = Some(id.clone()),
+                    None => self.head = Some(id.clone()),
+                }
+                Some(true)
+            }
+            CrdtOp::Delete { id } => {
+                let node = self.nodes.get_mut(id)?;
+                if node.deleted {
+                    return Some(false);
+                }
+                node.deleted = true;
+                Some(true)
+            }
+        }
+    }
+
+    /// 按文档顺序遍历字符
+    fn iter(&self) -> impl Iterator<Item = (&CharId, &Node)> {
+        std::iter::successors(self.head.as_ref().map(|id| (id, &self.nodes[id])), |(_, node)| {
+            node.next.as_ref().map(|id| (id, &self.nodes[id]))
+        })
+    }
+}
+
+/// 合并或生成 CRDT 操作失败
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CrdtError {
+    /// 操作依赖的字符不存在
+    MissingDependency(CharId),
+    /// 批内的插入互相依赖成环
+    Cycle(CharId),
+    /// 插入的时钟领先本地时钟太多或接近上限
+    ClockOutOfRange { clock: u64, current: u64 },
+    /// 本站点的时钟已用尽
+    ClockExhausted,
+}
+
+impl fmt::Display for CrdtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CrdtError::MissingDependency(id) => {
+                write!(f, "operation depends on unknown character {}@{}", id.clock, id.site)
+            }
+            CrdtError::Cycle(id) => write!(f, "character {}@{} depends on itself", id.clock, id.site),
+            CrdtError::ClockOutOfRange { clock, current } => {
+                write!(f, "clock {} is out of range for current clock {}", clock, current)
+            }
+            CrdtError::ClockExhausted => write!(f, "document clock is exhausted"),
+        }
+    }
+}
+
+impl std::error::Error for CrdtError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 三个站点基于同一初始文本并发编辑, 返回初始操作和各站点的操作
+    fn concurrent_edits() -> (Vec<CrdtOp>, Vec<Vec<CrdtOp>>) {
+        let mut origin = Rga::new("origin");
+        let base = origin.replace("hello world").unwrap();
+
+        let edits = [("a", "hello, world"), ("b", "hello brave world"), ("c", "help world!")];
+        let ops = edits
+            .iter()
+            .map(|(site, content)| {
+                let mut rga = Rga::new(site);
+                rga.merge(base.clone()).unwrap();
+                let mut ops = rga.replace(content).unwrap();
+                ops.extend(rga.replace(&format!("{content}.")).unwrap());
+                ops
+            })
+            .collect();
+        (base, ops)
+    }
+
+    #[test]
+    fn replace_produces_content() {
+        let mut rga = Rga::new("a");
+        rga.replace("hello").unwrap();
+        rga.replace("jello, world").unwrap();
+        assert_eq!(rga.text(), "jello, world");
+        assert!(rga.elements().iter().any(|e| e.deleted));
+    }
+
+    #[test]
+    fn merge_converges_in_any_order() {
+        let (base, ops) = concurrent_edits();
+        let orders: [[usize; 3]; 6] = [[0, 1, 2], [0, 2, 1], [1, 0, 2], [1, 2, 0], [2, 0, 1], [2, 1, 0]];
+
+        let mut texts = Vec::new();
+        for order in orders {
+            let mut rga = Rga::new("server");
+            rga.merge(base.clone()).unwrap();
+            for i in order {
+                rga.merge(ops[i].clone()).unwrap();
+            }
+            texts.push(rga.text());
+        }
+        assert!(texts.windows(2).all(|w| w[0] == w[1]), "{texts:?}");
+    }
+
+    #[test]
+    fn merge_reorders_within_batch() {
+        let (base, ops) = concurrent_edits();
+
+        let mut expected = Rga::new("server");
+        expected.merge(base.clone()).unwrap();
+        expected.merge(ops.concat()).unwrap();
+
+        // 同一批内的操作倒序到达, 依赖在后面才出现
+        let mut reversed: Vec<CrdtOp> = base.into_iter().chain(ops.concat()).collect();
+        reversed.reverse();
+        let mut rga = Rga::new("server");
+        let applied = rga.merge(reversed.clone()).unwrap();
+        assert_eq!(applied, reversed.len());
+        assert_eq!(rga.text(), expected.text());
+
+        // 重复合并不产生变化
+        assert_eq!(rga.merge(reversed), Ok(0));
+        assert_eq!(rga.text(), expected.text());
+    }
+
+    #[test]
+    fn merge_rejects_missing_dependencies() {
+        let (base, ops) = concurrent_edits();
+        let mut rga = Rga::new("server");
+        rga.merge(base[..3].to_vec()).unwrap();
+        let text = rga.text();
+
+        // 依赖的字符不在文档和本批中, 整批拒绝且不留下待合并的操作
+        let batch = [base[5..].to_vec(), base[3..4].to_vec()].concat();
+        assert!(matches!(rga.merge(batch), Err(CrdtError::MissingDependency(_))));
+        assert_eq!(rga.text(), text);
+        assert!(matches!(rga.merge(ops[0].clone()), Err(CrdtError::MissingDependency(_))));
+
+        rga.merge(base[3..].to_vec()).unwrap();
+        assert_eq!(rga.text(), "hello world");
+    }
+
+    fn insert(clock: u64, after: Option<u64>) -> CrdtOp {
+        let id = |clock| CharId { clock, site: "a".to_string() };
+        CrdtOp::Insert { id: id(clock), after: after.map(id), value: 'x' }
+    }
+
+    #[test]
+    fn merge_rejects_cycles() {
+        let mut rga = Rga::new("server");
+        rga.merge(vec![insert(1, None)]).unwrap();
+
+        // 互相依赖和依赖自身都整批拒绝, 同批中可合并的操作也不生效
+        let batch = vec![insert(2, Some(1)), insert(3, Some(4)), insert(4, Some(3))];
+        assert!(matches!(rga.merge(batch), Err(CrdtError::Cycle(_))));
+        let batch = vec![insert(2, Some(1)), insert(5, Some(5))];
+        assert!(matches!(rga.merge(batch), Err(CrdtError::Cycle(_))));
+        assert_eq!(rga.text(), "x");
+
+        // 批内逆序的依赖链仍可合并
+        assert_eq!(rga.merge(vec![insert(4, Some(3)), insert(3, Some(2)), insert(2, Some(1))]), Ok(3));
+        assert_eq!(rga.text(), "xxxx");
+    }
+
+    #[test]
+    fn merge_rejects_out_of_range_clocks() {
+        let mut rga = Rga::new("server");
+        rga.merge(vec![insert(1, None)]).unwrap();
+
+        for clock in [u64::MAX, 2 + MAX_CLOCK_STEP] {
+            assert!(matches!(
+                rga.merge(vec![insert(clock, Some(1))]),
+                Err(CrdtError::ClockOutOfRange { .. })
+            ));
+        }
+        assert_eq!(rga.merge(vec![insert(1 + MAX_CLOCK_STEP, Some(1))]), Ok(1));
+
+        // 时钟耗尽时不生成操作, 文档不变
+        let element = Element { id: CharId { clock: u64::MAX, site: "a".to_string() }, value: 'x', deleted: false };
+        let mut rga = Rga::from_elements("server", vec![element]);
+        assert_eq!(rga.replace("xy"), Err(CrdtError::ClockExhausted));
+        assert_eq!(rga.text(), "x");
+    }
+
+    #[test]
+    fn elements_round_trip() {
+        let (base, ops) = concurrent_edits();
+        let mut rga = Rga::new("server");
+        rga.merge(base).unwrap();
+        rga.merge(ops.concat()).unwrap();
+
+        let restored = Rga::from_elements("server", rga.elements());
+        assert_eq!(restored.text(), rga.text());
+        assert_eq!(restored.elements().len(), rga.elements().len());
+    }
+}
@@ -1,60 +1,325 @@
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
 
 use axum::{
     extract::{
-        ws::{WebSocket, WebSocketUpgrade, Message},
-        State,
+        ws::{close_code, CloseFrame, WebSocket, WebSocketUpgrade, Message},
+        Path, Query, State,
     },
-    response::IntoResponse,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     Error,
 };
 use futures_util::{SinkExt, StreamExt};
-use serde::{Serialize, Deserialize};
-use tokio::time::{timeout, Duration};
-use crate::app::{AppState, Document};
+use serde::Deserialize;
+use tokio::sync::{broadcast::{self, error::RecvError}, mpsc};
+use tokio::time::{timeout, Duration, Instant};
+use tungstenite::error::CapacityError;
+use crate::acl::Role;
+use crate::app::{AppState, Author, Document, DocumentKind, User};
+use crate::auth::{self, Claims};
+use crate::crdt::CrdtOp;
+use crate::ot::{Operation, OtError};
+use crate::protocol::{self, ClientMessage, Encoding, ErrorCode, Negotiated, ServerMessage};
+use crate::ratelimit::{RateLimiter, Verdict};
 
-const CONNECTION_TEST_TIMEOUT: Duration = Duration::from_millis(100);
+/// `/ws` 未指定文档时使用的文档ID
+const DEFAULT_DOCUMENT_ID: &str = "default";
 
-#[derive(Debug, Serialize, Deserialize)]
-struct WebSocketMessage {
-    r#type: String,
-    payload: serde_json::Value,
+/// 发往单个连接的消息
+enum Outgoing {
+    Message(ServerMessage),
+    /// 之后的消息改用新的编码
+    SetEncoding(Encoding),
+    /// 发送关闭帧后断开连接
+    Close { code: u16, reason: String },
+}
+
+/// 单个连接的上下文
+struct Connection {
+    doc_id: String,
+    user_id: String,
+    connection_id: String,
+    // 仅发给本连接的消息 (如冲突、错误)
+    outgoing: mpsc::UnboundedSender<Outgoing>,
+    // 连接参数指定的发送编码, hello 未指定编码时沿用
+    encoding: Encoding,
+    // 通过 hello 协商的协议, 未握手的旧客户端按 v1 处理
+    negotiated: Option<Negotiated>,
+    limiter: RateLimiter,
+    // 已要求关闭连接 (限流或消息过大), 之后收到的消息全部丢弃
+    closing: bool,
+}
+
+impl Connection {
+    /// 是否协商了功能 `feature`; 未握手的旧客户端启用全部功能
+    fn supports(&self, feature: &str) -> bool {
+        self.negotiated.as_ref().is_none_or(|negotiated| negotiated.features.iter().any(|f| f == feature))
+    }
+
+    fn reply(&self, message: ServerMessage) {
+        let _ = self.outgoing.send(Outgoing::Message(message));
+    }
+
+    fn close(&self, code: u16, reason: impl Into<String>) {
+        let _ = self.outgoing.send(Outgoing::Close { code, reason: reason.into() });
+    }
+
+    /// 按限流决定是否处理收到的 `len` 字节消息; 持续超出限制时关闭连接
+    fn admit(&mut self, state: &AppState, len: usize) -> bool {
+        if self.closing {
+            return false;
+        }
+        match self.limiter.check(len) {
+            Verdict::Allow => true,
+            Verdict::Reject => {
+                state.metrics().rate_limited_messages.fetch_add(1, Ordering::Relaxed);
+                self.reply(ServerMessage::error(ErrorCode::RateLimited, "too many messages, slow down"));
+                false
+            }
+            Verdict::Disconnect => {
+                tracing::warn!(doc_id = %self.doc_id, "Disconnecting user {}: rate limit exceeded repeatedly", self.user_id);
+                state.metrics().rate_limited_messages.fetch_add(1, Ordering::Relaxed);
+                state.metrics().rate_limit_disconnects.fetch_add(1, Ordering::Relaxed);
+                self.close(close_code::POLICY, "rate limit exceeded");
+                self.closing = true;
+                false
+            }
+        }
+    }
+}
+
+/// 连接参数, 如 `/ws/{doc_id}?backend=crdt&encoding=msgpack`
+#[derive(Debug, Default, Deserialize)]
+pub struct ConnectParams {
+    // 文档不存在时以此后端创建
+    #[serde(default)]
+    backend: DocumentKind,
+    // 服务器发送消息的初始编码, 大文档的首个快照也能使用二进制
+    #[serde(default)]
+    encoding: Encoding,
+    // 显示名称和光标颜色 (#rrggbb), 未指定时由服务器生成
+    name: Option<String>,
+    color: Option<String>,
+    // 认证令牌, 也可通过 Authorization 头传递
+    token: Option<String>,
+    // 重连时恢复会话: 上次连接收到的恢复令牌及客户端已确认的文档版本
+    resume: Option<String>,
+    version: Option<u64>,
+}
+
+/// 按连接的编码把消息编为 WebSocket 帧
+fn encode(message: &ServerMessage, encoding: Encoding) -> Message {
+    match encoding {
+        Encoding::Json => Message::Text(message.to_json().into()),
+        Encoding::Msgpack => Message::Binary(message.to_msgpack().into()),
+    }
 }
 
 pub async fn websocket_handler(
     ws: WebSocketUpgrade,
+    headers: HeaderMap,
+    Query(params): Query<ConnectParams>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    let doc_id = DEFAULT_DOCUMENT_ID.to_string();
+    upgrade(ws, &headers, state, doc_id, params)
+}
+
+/// 连接到指定文档: `/ws/{doc_id}`
+pub async fn document_websocket_handler(
+    ws: WebSocketUpgrade,
+    headers: HeaderMap,
+    Path(doc_id): Path<String>,
+    Query(params): Query<ConnectParams>,
     State(state): State<Arc<AppState>>,
-) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_websocket_connection(socket, (*state).clone()))
+) -> Response {
+    upgrade(ws, &headers, state, doc_id, params)
+}
+
+/// 认证通过后升级为 WebSocket 连接, 否则返回 401
+fn upgrade(
+    ws: WebSocketUpgrade,
+    headers: &HeaderMap,
+    state: Arc<AppState>,
+    doc_id: String,
+    params: ConnectParams,
+) -> Response {
+    let claims = match authenticate(&state, headers, &params) {
+        Ok(claims) => claims,
+        Err(reason) => {
+            tracing::info!(doc_id = %doc_id, "Rejected connection: {}", reason);
+            return (StatusCode::UNAUTHORIZED, reason).into_response();
+        }
+    };
+    // 超出大小的消息在读取时即被拒绝, 不会进入解析
+    let max_message_bytes = state.limits().max_message_bytes;
+    ws.max_message_size(max_message_bytes)
+        .max_frame_size(max_message_bytes)
+        .on_upgrade(move |socket| handle_websocket_connection(socket, (*state).clone(), doc_id, claims, params))
+}
+
+/// 配置了认证时校验令牌; 未配置时返回 `None`, 每个连接作为一个匿名用户
+fn authenticate(state: &AppState, headers: &HeaderMap, params: &ConnectParams) -> Result<Option<Claims>, String> {
+    let Some(authenticator) = state.authenticator() else {
+        return Ok(None);
+    };
+    let token = auth::bearer_token(headers, params.token.as_deref())
+        .ok_or_else(|| "missing bearer token".to_string())?;
+    authenticator
+        .verify(token)
+        .map(Some)
+        .map_err(|e| format!("invalid token: {}", e))
 }
 
 async fn handle_websocket_connection(
     mut socket: WebSocket,
     state: AppState,
+    doc_id: String,
+    claims: Option<Claims>,
+    params: ConnectParams,
 ) {
-    let user_id = uuid::Uuid::new_v4().to_string();
-    tracing::info!("User {} connecting", user_id);
+    // 凭令牌恢复之前的会话, 已认证的连接只能恢复属于自己的会话
+    let owner = claims.as_ref().map(|claims| claims.sub.as_str());
+    let session = params.resume.as_deref().and_then(|token| state.take_session(token, &doc_id, owner));
+    let resumed = session.is_some();
+    let authenticated = claims.is_some();
+
+    // 已认证的连接使用令牌中的用户ID, 同一用户的多个连接以连接ID区分
+    let (connection_id, user_id, name, color) = match (session, claims) {
+        (Some(session), _) => (session.connection_id, session.user_id, Some(session.name), Some(session.color)),
+        (None, Some(claims)) => {
+            let connection_id = uuid::Uuid::new_v4().to_string();
+            (connection_id, claims.sub, claims.name.or(params.name), params.color)
+        }
+        (None, None) => {
+            let connection_id = uuid::Uuid::new_v4().to_string();
+            (connection_id.clone(), connection_id, params.name, params.color)
+        }
+    };
+    tracing::info!(doc_id = %doc_id, connection_id = %connection_id, resumed, "User {} connecting", user_id);
 
-    if let Err(e) = test_connection(&mut socket, &state, &user_id).await {
+    // 首次访问时加载文档, 不存在则按请求的后端创建, 已存在的文档保持原后端
+    let creator = authenticated.then_some(user_id.as_str());
+    let kind = match state.load_document(&doc_id, params.backend, creator).await {
+        Ok(kind) => kind,
+        Err(e) => {
+            tracing::error!(doc_id = %doc_id, "Failed to load document: {}", e);
+            return;
+        }
+    };
+    if kind != params.backend {
+        tracing::debug!(doc_id = %doc_id, "Document already exists with backend {:?}", kind);
+    }
+
+    // 没有任何角色的用户不能打开文档
+    if state.documents.get(&doc_id).and_then(|doc| doc.role(&user_id)).is_none() {
+        tracing::info!(doc_id = %doc_id, "User {} has no access to document", user_id);
+        let error = ServerMessage::error(ErrorCode::Forbidden, "no access to document");
+        let _ = socket.send(encode(&error, params.encoding)).await;
+        let frame = CloseFrame { code: close_code::POLICY, reason: "forbidden".into() };
+        let _ = socket.send(Message::Close(Some(frame))).await;
+        return;
+    }
+
+    let since = params.version.filter(|_| resumed);
+    let (mut broadcast_rx, doc_msg) = initial_state(&state, &doc_id, &connection_id, since);
+    if let Err(e) = test_connection(&mut socket, &state, &connection_id, &doc_msg, params.encoding).await {
         tracing::warn!(user_id = %user_id, error = %e, "Connection test failed");
+        drop(broadcast_rx);
+        state.release_channel(&doc_id);
         return
     }
 
-    // 生成广播接收器
-    let mut broadcast_rx = state.tx.subscribe();
+    let (outgoing_tx, mut outgoing_rx) = mpsc::unbounded_channel::<Outgoing>();
 
-    // 添加到用户状态
-    let user_count = state.add_user(user_id.clone());
-    broadcast_user_count(&state, user_count).await;
+    // 添加到用户状态, 新连接先收到文档中的全部用户, 其他人收到加入事件
+    let user = User::new(user_id.clone(), connection_id.clone(), doc_id.clone(), name, color);
+    let joined = user.presence();
+    let disconnect = user.disconnect_signal();
+    let _ = outgoing_tx.send(Outgoing::Message(ServerMessage::Session {
+        resume_token: user.resume_token().to_string(),
+        resumed,
+    }));
+    if let Some(metadata) = state.documents.get(&doc_id).map(|doc| doc.metadata().clone()) {
+        let _ = outgoing_tx.send(Outgoing::Message(ServerMessage::Metadata(metadata)));
+    }
+    let user_count = state.add_user(user);
+    let _ = outgoing_tx.send(Outgoing::Message(ServerMessage::PresenceSnapshot {
+        user_id: user_id.clone(),
+        connection_id: connection_id.clone(),
+        users: state.document_presence(&doc_id),
+    }));
+    state.broadcast(&doc_id, ServerMessage::UserJoined(joined));
+    broadcast_user_count(&state, &doc_id, user_count).await;
 
     // 同时处理发送和接收消息
     let (mut sender, mut receiver) = socket.split();
+    // ping 的负载为发送时距连接开始的毫秒数, 收到 pong 时据此计算往返延迟
+    let started = Instant::now();
+    let missed_pongs = Arc::new(AtomicU32::new(0));
 
     let mut send_task = tokio::spawn({
+        let state = state.clone();
+        let doc_id = doc_id.clone();
         let user_id = user_id.clone();
+        let mut encoding = params.encoding;
+        let missed_pongs = missed_pongs.clone();
         async move {
-            while let Ok(msg) = broadcast_rx.recv().await {
-                if let Err(e) = sender.send(Message::Text(msg.into())).await {
+            let config = *state.connection_config();
+            let mut heartbeat = tokio::time::interval(config.heartbeat_interval());
+            loop {
+                let msg = tokio::select! {
+                    msg = broadcast_rx.recv() => match msg {
+                        Ok(msg) => encode(&msg, encoding),
+                        // 客户端太慢, 广播通道中的消息已被覆盖, 改发最新快照后继续;
+                        // 持有文档锁时重新订阅, 丢弃积压的已包含在快照中的消息
+                        Err(RecvError::Lagged(skipped)) => {
+                            tracing::warn!(user_id = %user_id, doc_id = %doc_id, "Client lagged behind by {} messages, resending snapshot", skipped);
+                            state.record_lag(skipped);
+                            let Some(doc) = state.documents.get(&doc_id) else {
+                                continue;
+                            };
+                            broadcast_rx = broadcast_rx.resubscribe();
+                            let snapshot = snapshot_message(&doc, None);
+                            drop(doc);
+                            encode(&snapshot, encoding)
+                        }
+                        // 只有删除文档时才会关闭仍有订阅者的通道
+                        Err(RecvError::Closed) => {
+                            let frame = CloseFrame { code: close_code::NORMAL, reason: "document deleted".into() };
+                            let _ = sender.send(Message::Close(Some(frame))).await;
+                            break;
+                        }
+                    },
+                    Some(outgoing) = outgoing_rx.recv() => match outgoing {
+                        Outgoing::Message(msg) => encode(&msg, encoding),
+                        Outgoing::SetEncoding(new_encoding) => {
+                            encoding = new_encoding;
+                            continue;
+                        }
+                        Outgoing::Close { code, reason } => {
+                            let frame = CloseFrame { code, reason: reason.into() };
+                            let _ = sender.send(Message::Close(Some(frame))).await;
+                            break;
+                        }
+                    },
+                    _ = heartbeat.tick() => {
+                        if missed_pongs.fetch_add(1, Ordering::Relaxed) >= config.max_missed_pongs {
+                            tracing::info!(user_id = %user_id, "No pong for {} pings, dropping connection", config.max_missed_pongs);
+                            break;
+                        }
+                        let sent_at = started.elapsed().as_millis() as u64;
+                        Message::Ping(sent_at.to_be_bytes().to_vec().into())
+                    }
+                    // 空闲超时等由服务器主动断开
+                    _ = disconnect.notified() => {
+                        let frame = CloseFrame { code: close_code::AWAY, reason: "idle timeout".into() };
+                        let _ = sender.send(Message::Close(Some(frame))).await;
+                        break;
+                    }
+                };
+                if let Err(e) = sender.send(msg).await {
                     tracing::warn!(user_id = %user_id, "Failed to send message to socket: {}", e);
                     break;
                 }
@@ -65,64 +330,129 @@ async fn handle_websocket_connection(
     let mut recv_task = tokio::spawn({
         let state = state.clone();
         let user_id = user_id.clone();
+        let mut conn = Connection {
+            doc_id: doc_id.clone(),
+            user_id: user_id.clone(),
+            connection_id: connection_id.clone(),
+            outgoing: outgoing_tx,
+            encoding: params.encoding,
+            negotiated: None,
+            limiter: RateLimiter::new(state.rate_limit_config()),
+            closing: false,
+        };
         async move {
             while let Some(message) = receiver.next().await {
                 match message {
                     Ok(Message::Text(text)) => {
+                        if !conn.admit(&state, text.len()) {
+                            continue;
+                        }
                         // 处理文本消息
-                        handle_text_message(&text, &state, &user_id).await;
-                        // state.update_user_activity(&user_id);
+                        handle_message(ClientMessage::from_json(&text), &state, &mut conn).await;
+                        state.update_user_activity(&conn.connection_id);
+                    }
+                    Ok(Message::Binary(bytes)) => {
+                        if !conn.admit(&state, bytes.len()) {
+                            continue;
+                        }
+                        // 二进制帧为 MessagePack 编码的同一协议消息
+                        handle_message(ClientMessage::from_msgpack(&bytes), &state, &mut conn).await;
+                        state.update_user_activity(&conn.connection_id);
+                    }
+                    Ok(Message::Pong(payload)) => {
+                        // 忽略负载不是本连接 ping 的 pong; pong 不算作用户活动
+                        if let Ok(sent_at) = <[u8; 8]>::try_from(payload.as_ref()) {
+                            let sent_at = Duration::from_millis(u64::from_be_bytes(sent_at));
+                            missed_pongs.store(0, Ordering::Relaxed);
+                            state.update_latency(&conn.connection_id, started.elapsed().saturating_sub(sent_at));
+                        }
                     }
                     Ok(Message::Close(_)) => {
                         tracing::info!(user_id = %user_id, "Socket requested close");
                         break;
                     }
+                    Err(_) if conn.closing => {}
                     Err(e) => {
                         tracing::warn!(user_id = %user_id, "WebSocket error: {}", e);
+                        // 超出大小上限后连接的读取状态已不可用, 回复错误后关闭
+                        if let Some(e) = capacity_error(e) {
+                            conn.reply(ServerMessage::error(ErrorCode::PayloadTooLarge, e.to_string()));
+                            conn.close(close_code::SIZE, "message too large");
+                            conn.closing = true;
+                        }
                     }
                     _ => {} //忽略其他消息类型
                 }
             }
+            // 已要求关闭时, 等发送任务发出排队的错误和关闭帧后再结束连接
+            if conn.closing {
+                std::future::pending::<()>().await;
+            }
         }
     });
 
+    // 等待被中止的任务结束, 确保广播接收器已释放
     tokio::select! {
-        _ = &mut send_task => recv_task.abort(),
-        _ = &mut recv_task => send_task.abort(),
+        _ = &mut send_task => {
+            recv_task.abort();
+            let _ = recv_task.await;
+        }
+        _ = &mut recv_task => {
+            send_task.abort();
+            let _ = send_task.await;
+        }
     }
 
     // 清理资源
-    cleanup_connection(&state, &user_id).await;
+    cleanup_connection(&state, &user_id, &connection_id).await;
+}
+
+/// 订阅文档的广播并生成发给新连接的文档状态: CRDT 文档同时以连接ID作为站点ID;
+/// 恢复的会话只补发 `since` 之后错过的操作, 无法补齐时仍发送完整快照
+///
+/// 编辑在持有文档写锁时广播, 这里持有读锁订阅, 快照之后提交的编辑一定会出现在广播中;
+/// 广播中版本不超过快照的消息由客户端丢弃
+fn initial_state(
+    state: &AppState,
+    doc_id: &str,
+    connection_id: &str,
+    since: Option<u64>,
+) -> (broadcast::Receiver<Arc<ServerMessage>>, ServerMessage) {
+    let doc = state.documents.get(doc_id);
+    let broadcast_rx = state.subscribe(doc_id);
+    let doc_msg = doc
+        .map(|doc| {
+            since
+                .and_then(|since| doc.operations_since(since, connection_id))
+                .map(|operations| ServerMessage::MissedOperations { operations, version: doc.version() })
+                .unwrap_or_else(|| snapshot_message(&doc, Some(connection_id)))
+        })
+        .unwrap_or_else(|| snapshot_message(&Document::default(), None));
+    (broadcast_rx, doc_msg)
 }
 
-/// 快速连接测试: 立即发送测试消息验证连接有效性
+/// 立即发送文档状态测试连接
 async fn test_connection(
     socket: &mut WebSocket,
     state: &AppState,
-    user_id: &str,
+    connection_id: &str,
+    doc_msg: &ServerMessage,
+    encoding: Encoding,
 ) -> Result<(), Error> {
-    let (content, version) = state.documents.get("default")
-        .map(|doc| (doc.content().to_string(), doc.version()))
-        .unwrap_or_default();
-    // 立即发送当前状态文档测试连接
-    let doc_msg = serde_json::json!({
-        "type": "content_update",
-        "payload": { "content": content, "version": version }
-    });
     match timeout(
-        CONNECTION_TEST_TIMEOUT,
-        socket.send(Message::Text(serde_json::to_string(&doc_msg).unwrap().into()))
+        state.connection_config().test_timeout(),
+        socket.send(encode(doc_msg, encoding))
     ).await {
         Ok(Ok(())) => {
-            tracing::debug!(user_id = %user_id, "Connection test passed");
+            tracing::debug!(connection_id = %connection_id, "Connection test passed");
             Ok(())
         }
         Ok(Err(e)) => {
-            tracing::warn!(user_id = %user_id, "Connection test failed - send error");
+            tracing::warn!(connection_id = %connection_id, "Connection test failed - send error");
             Err(e)
         }
         Err(_) => {
-            tracing::warn!(user_id = %user_id, "Connection test failed - timeout");
+            tracing::warn!(connection_id = %connection_id, "Connection test failed - timeout");
             Err(Error::new(std::io::Error::new(
                 std::io::ErrorKind::TimedOut, 
             "connection test timeout"
@@ -131,51 +461,345 @@ async fn test_connection(
     }
 }
 
-async fn handle_text_message(text: &str, state: &AppState, user_id: &str) {
-    match serde_json::from_str::<WebSocketMessage>(text) {
-        Ok(message) => {
-            match message.r#type.as_str() {
-                "content_update" => {
-                    if let Some(content) = message.payload.get("content").and_then(|v| v.as_str()) {
-                        // 更新文档内容
-                        let mut doc = state.documents.entry("default".to_string()).or_insert_with(|| Document::default());
-                        doc.update(content);
+/// 消息依赖的可选功能: OT 操作、CRDT 操作, 以及带版本、冲突时回复 `conflict` 的整体更新
+fn required_feature(message: &ClientMessage) -> Option<&'static str> {
+    match message {
+        ClientMessage::Operation { .. } => Some("operation"),
+        ClientMessage::CrdtOperation { .. } => Some("crdt"),
+        ClientMessage::ContentUpdate { version: Some(_), .. } => Some("conflict"),
+        _ => None,
+    }
+}
+
+async fn handle_message(message: Result<ClientMessage, String>, state: &AppState, conn: &mut Connection) {
+    match message {
+        Ok(message) => handle_client_message(message, state, conn),
+        Err(e) => {
+            tracing::warn!("Failed to parse message from user {}: {}", conn.user_id, e);
+            conn.reply(ServerMessage::error(ErrorCode::InvalidMessage, e));
+        }
+    }
+}
+
+fn handle_client_message(message: ClientMessage, state: &AppState, conn: &mut Connection) {
+    let (doc_id, user_id, connection_id) = (conn.doc_id.as_str(), conn.user_id.as_str(), conn.connection_id.as_str());
+    let author = Author { user_id: Some(user_id), connection_id: Some(connection_id) };
+    let max_document_bytes = state.limits().max_document_bytes;
+    if let Some(feature) = required_feature(&message).filter(|feature| !conn.supports(feature)) {
+        tracing::warn!("Rejected message from user {}: feature {} was not negotiated", user_id, feature);
+        conn.reply(ServerMessage::error(ErrorCode::Unsupported, format!("feature {} was not negotiated", feature)));
+        return;
+    }
+    match message {
+        ClientMessage::Hello { versions, features, encoding } => {
+            if conn.negotiated.is_some() {
+                conn.reply(ServerMessage::error(ErrorCode::InvalidMessage, "handshake already completed"));
+                return;
+            }
+            match protocol::negotiate(&versions, features, encoding.unwrap_or(conn.encoding)) {
+                Ok(negotiated) => {
+                    tracing::debug!(user_id = %user_id, "Negotiated protocol {:?}", negotiated);
+                    // welcome 仍使用原编码, 之后的消息改用协商的编码
+                    conn.reply(ServerMessage::Welcome {
+                        protocol_version: negotiated.version,
+                        features: negotiated.features.clone(),
+                        encoding: negotiated.encoding,
+                    });
+                    let _ = conn.outgoing.send(Outgoing::SetEncoding(negotiated.encoding));
+                    conn.negotiated = Some(negotiated);
+                }
+                Err(reason) => {
+                    tracing::info!(user_id = %user_id, "Rejected protocol versions {:?}", versions);
+                    conn.reply(ServerMessage::error(ErrorCode::UnsupportedVersion, reason.clone()));
+                    conn.close(close_code::PROTOCOL, reason);
+                }
+            }
+        }
+        ClientMessage::ContentUpdate { content, version } => {
+            let Some(mut doc) = state.documents.get_mut(doc_id) else {
+                document_deleted(conn);
+                return;
+            };
+            if !doc.role(user_id).is_some_and(Role::can_edit) {
+                forbidden(conn, "editing requires the editor role");
+                return;
+            }
 
-                        // 广播更新
-                        let broadcast_msg = serde_json::json!({
-                            "type": "content_update",
-                            "payload": { "content": content, "version": doc.version() }
-                        });
+            // 客户端带了基础版本时做乐观并发检查, 版本过期则拒绝并返回最新内容供其变基
+            if let Some(base_version) = version.filter(|v| *v != doc.version()) {
+                tracing::info!(
+                    "Conflicting update from user {}: base version {}, current {}",
+                    user_id, base_version, doc.version()
+                );
+                conn.reply(ServerMessage::Conflict {
+                    content: doc.content().to_string(),
+                    version: doc.version(),
+                });
+                return;
+            }
+            if content.len() > max_document_bytes {
+                too_large(conn, content.len(), max_document_bytes);
+                return;
+            }
 
-                        if let Ok(msg_str) = serde_json::to_string(&broadcast_msg) {
-                            let _ = state.tx.send(msg_str);
-                        }
-                    }
+            // 更新文档内容
+            let diff = match doc.update(&content, author) {
+                Ok(diff) => diff,
+                Err(e) => {
+                    tracing::warn!("Rejected update from user {}: {}", user_id, e);
+                    conn.reply(ServerMessage::error(ErrorCode::InvalidOperation, e.to_string()));
+                    return;
                 }
-                _ => {
-                    tracing::info!("Unknown message type from user {}: {}", user_id, message.r#type);
+            };
+            state.transform_selections(doc_id, &diff);
+            state.mark_dirty(doc_id);
+
+            // 广播更新
+            state.broadcast(doc_id, snapshot_message(&doc, None));
+        }
+        ClientMessage::Operation { version, ops } => {
+            let Some(mut doc) = state.documents.get_mut(doc_id) else {
+                document_deleted(conn);
+                return;
+            };
+            if !doc.role(user_id).is_some_and(Role::can_edit) {
+                forbidden(conn, "editing requires the editor role");
+                return;
+            }
+            match doc.apply_operation(version, ops, author, max_document_bytes) {
+                Ok(transformed) => {
+                    state.transform_selections(doc_id, &transformed);
+                    state.mark_dirty(doc_id);
+                    // 广播转换后的操作及新版本号, 发送者据 user_id 确认自己的操作
+                    state.broadcast(doc_id, ServerMessage::Operation {
+                        ops: transformed,
+                        version: doc.version(),
+                        user_id: user_id.to_string(),
+                        connection_id: connection_id.to_string(),
+                    });
+                }
+                Err(e) => {
+                    tracing::warn!("Rejected operation from user {}: {}", user_id, e);
+                    let code = match e {
+                        OtError::Unsupported => ErrorCode::Unsupported,
+                        OtError::TooLarge { .. } => ErrorCode::PayloadTooLarge,
+                        _ => ErrorCode::InvalidOperation,
+                    };
+                    conn.reply(ServerMessage::error(code, e.to_string()));
                 }
             }
         }
-        Err(e) => {
-            tracing::warn!("Failed to parse message from user {}: {}", user_id, e);
+        ClientMessage::CrdtOperation { ops } => {
+            let Some(mut doc) = state.documents.get_mut(doc_id) else {
+                document_deleted(conn);
+                return;
+            };
+            if !doc.role(user_id).is_some_and(Role::can_edit) {
+                forbidden(conn, "editing requires the editor role");
+                return;
+            }
+            // 按全部插入都生效估算合并后的大小, 重复的插入可能使估算偏大
+            let inserted: usize = ops
+                .iter()
+                .map(|op| match op {
+                    CrdtOp::Insert { value, .. } => value.len_utf8(),
+                    CrdtOp::Delete { .. } => 0,
+                })
+                .sum();
+            if doc.content().len() + inserted > max_document_bytes {
+                too_large(conn, doc.content().len() + inserted, max_document_bytes);
+                return;
+            }
+            let before = doc.content().to_string();
+            match doc.merge_crdt(ops.clone(), author) {
+                Ok(true) => {
+                    state.transform_selections(doc_id, &Operation::diff(&before, doc.content()));
+                    state.mark_dirty(doc_id);
+                }
+                Ok(false) => {}
+                Err(OtError::Unsupported) => {
+                    tracing::warn!("Rejected CRDT operation from user {}: document is not a CRDT document", user_id);
+                    conn.reply(ServerMessage::error(ErrorCode::Unsupported, "document is not a CRDT document"));
+                    return;
+                }
+                Err(e) => {
+                    tracing::warn!("Rejected CRDT operation from user {}: {}", user_id, e);
+                    conn.reply(ServerMessage::error(ErrorCode::InvalidOperation, e.to_string()));
+                    return;
+                }
+            }
+
+            // 原样转发, 各站点的合并是幂等的
+            state.broadcast(doc_id, ServerMessage::CrdtOperation {
+                ops,
+                version: doc.version(),
+                user_id: user_id.to_string(),
+                connection_id: connection_id.to_string(),
+            });
+        }
+        ClientMessage::CursorPosition { selection, version } => {
+            // 持有文档锁直到广播, 保证广播的选区与版本号一致
+            let Some(doc) = state.documents.get(doc_id) else {
+                document_deleted(conn);
+                return;
+            };
+            if doc.role(user_id).is_none() {
+                forbidden(conn, "no access to document");
+                return;
+            }
+            match doc.transform_selection(version, selection) {
+                Ok(selection) => {
+                    state.set_selection(connection_id, selection);
+                    state.broadcast(doc_id, ServerMessage::CursorPosition {
+                        user_id: user_id.to_string(),
+                        connection_id: connection_id.to_string(),
+                        selection,
+                        version: doc.version(),
+                    });
+                }
+                Err(e) => {
+                    tracing::debug!("Rejected cursor position from user {}: {}", user_id, e);
+                    conn.reply(ServerMessage::error(ErrorCode::InvalidOperation, e.to_string()));
+                }
+            }
         }
     }
 }
 
-async fn broadcast_user_count(state: &AppState, count: usize) {
-    let message = serde_json::json!({
-        "type": "user_count_update",
-        "payload": { "count": count }
-    });
+fn forbidden(conn: &Connection, reason: &str) {
+    tracing::info!(doc_id = %conn.doc_id, "Forbidden message from user {}: {}", conn.user_id, reason);
+    conn.reply(ServerMessage::error(ErrorCode::Forbidden, reason));
+}
+
+/// 文档已被删除 (删除时广播通道关闭, 消息可能先于关闭到达), 回复后关闭连接
+fn document_deleted(conn: &Connection) {
+    tracing::info!(doc_id = %conn.doc_id, "Message from user {} for deleted document", conn.user_id);
+    conn.reply(ServerMessage::error(ErrorCode::NotFound, "document deleted"));
+    conn.close(close_code::NORMAL, "document deleted");
+}
+
+/// 读取时超出 WebSocket 消息或帧大小上限的错误
+fn capacity_error(error: axum::Error) -> Option<CapacityError> {
+    match *error.into_inner().downcast::<tungstenite::Error>().ok()? {
+        tungstenite::Error::Capacity(e) => Some(e),
+        _ => None,
+    }
+}
+
+fn too_large(conn: &Connection, len: usize, max: usize) {
+    tracing::info!(doc_id = %conn.doc_id, "Rejected edit from user {}: document would be {} bytes", conn.user_id, len);
+    conn.reply(ServerMessage::error(
+        ErrorCode::PayloadTooLarge,
+        format!("document would grow to {} bytes, the limit is {}", len, max),
+    ));
+}
+
+/// 文档当前状态: OT 文档为 `content_update`, CRDT 文档为带全部元素的 `crdt_snapshot`
+pub(crate) fn snapshot_message(doc: &Document, site_id: Option<&str>) -> ServerMessage {
+    match doc.crdt_elements() {
+        Some(elements) => ServerMessage::CrdtSnapshot {
+            content: doc.content().to_string(),
+            version: doc.version(),
+            elements,
+            site_id: site_id.map(str::to_string),
+        },
+        None => ServerMessage::ContentUpdate {
+            content: doc.content().to_string(),
+            version: doc.version(),
+        },
+    }
+}
 
-    if let Ok(msg_str) = serde_json::to_string(&message) {
-        let _ = state.tx.send(msg_str);
+async fn broadcast_user_count(state: &AppState, doc_id: &str, count: usize) {
+    state.broadcast(doc_id, ServerMessage::UserCountUpdate { count });
+}
+
+async fn cleanup_connection(state: &AppState, user_id: &str, connection_id: &str) {
+    if let Some((doc_id, user_count)) = state.remove_user(connection_id) {
+        state.broadcast(&doc_id, ServerMessage::UserLeft {
+            user_id: user_id.to_string(),
+            connection_id: connection_id.to_string(),
+        });
+        broadcast_user_count(state, &doc_id, user_count).await;
+        state.release_channel(&doc_id);
+        tracing::info!(doc_id = %doc_id, "User {} removed, {} users remaining", user_id, user_count);
     }
 }
+#[cfg(test)]
+mod tests {
+    use axum::routing::get;
+    use tokio_tungstenite::tungstenite::{self, protocol::frame::coding::CloseCode};
+
+    use super::*;
+    use crate::config::Config;
+    use crate::storage::FileStore;
+
+    /// 启动只有文档 WebSocket 路由的服务器, 返回监听地址
+    async fn serve(config: Config) -> std::net::SocketAddr {
+        let dir = std::env::temp_dir().join(format!("realtime-editor-test-{}", uuid::Uuid::new_v4()));
+        let store = Arc::new(FileStore::new(dir).unwrap());
+        let state = Arc::new(AppState::new(store, &config, None));
+        let app = axum::Router::new()
+            .route("/ws/{doc_id}", get(document_websocket_handler))
+            .with_state(state);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        addr
+    }
+
+    // 依赖 axum 与本 crate 的 tungstenite 版本一致, 否则超限错误无法识别
+    #[tokio::test]
+    async fn oversized_message_is_rejected_and_closed() {
+        let mut config = Config::default();
+        config.limits.max_message_bytes = 256;
+        let addr = serve(config).await;
+
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws/doc", addr)).await.unwrap();
+        let content = "x".repeat(1024);
+        let message = serde_json::json!({ "type": "content_update", "payload": { "content": content } });
+        ws.send(tungstenite::Message::text(message.to_string())).await.unwrap();
+
+        let mut too_large = false;
+        let close = loop {
+            match ws.next().await {
+                Some(Ok(tungstenite::Message::Text(text))) => {
+                    let message: serde_json::Value = serde_json::from_str(&text).unwrap();
+                    too_large |= message["type"] == "error" && message["payload"]["code"] == "payload_too_large";
+                }
+                Some(Ok(tungstenite::Message::Close(frame))) => break frame,
+                Some(Ok(_)) => {}
+                other => panic!("connection ended without a close frame: {:?}", other),
+            }
+        };
+        assert!(too_large, "expected a payload_too_large error");
+        assert_eq!(close.map(|frame| frame.code), Some(CloseCode::Size));
+    }
+
+    #[tokio::test]
+    async fn hello_without_encoding_keeps_connection_encoding() {
+        let addr = serve(Config::default()).await;
 
-async fn cleanup_connection(state: &AppState, user_id: &str) {
-    let user_count = state.remove_user(user_id);
-    broadcast_user_count(state, user_count).await;
-    tracing::info!("User {} removed, {} users remaining", user_id, user_count);
-}
\ No newline at end of file
+        let url = format!("ws://{}/ws/doc?encoding=msgpack", addr);
+        let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let hello = serde_json::json!({ "type": "hello", "payload": { "versions": [1] } });
+        ws.send(tungstenite::Message::text(hello.to_string())).await.unwrap();
+        ws.send(tungstenite::Message::text(r#"{"type":"unknown"}"#)).await.unwrap();
+
+        // 握手之后的错误回复仍以 MessagePack 发送
+        loop {
+            match ws.next().await {
+                Some(Ok(tungstenite::Message::Binary(bytes))) => {
+                    let message: serde_json::Value = rmp_serde::from_slice(&bytes).unwrap();
+                    if message["type"] == "error" {
+                        assert_eq!(message["payload"]["code"], "invalid_message");
+                        break;
+                    }
+                }
+                Some(Ok(tungstenite::Message::Text(text))) => panic!("unexpected text frame: {}", text),
+                Some(Ok(_)) => {}
+                other => panic!("connection ended before the error reply: {:?}", other),
+            }
+        }
+    }
+}
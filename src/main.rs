@@ -1,29 +1,120 @@
+mod acl;
+mod api;
 mod app;
+mod auth;
+mod config;
+mod crdt;
 mod handler;
+mod ot;
+mod protocol;
+mod ratelimit;
+mod storage;
+mod tls;
 
 use std::sync::Arc;
-use axum::routing::get;
+use axum::extract::DefaultBodyLimit;
+use axum::routing::{get, post, put};
+use clap::Parser;
 use app::AppState;
-use handler::websocket_handler;
+use config::{Cli, Config, StorageBackend};
+use handler::{document_websocket_handler, websocket_handler};
+use storage::{DocumentStore, FileStore, RedbStore};
 
 #[tokio::main]
 async fn main() {
+    // 配置: 默认值 < 配置文件 < 环境变量 < 命令行参数
+    let config = Config::load(Cli::parse()).unwrap_or_else(|e| exit_with_error(e));
+
     // 初始化日志
     tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
+        .with_max_level(config.log_level().expect("validated log level"))
         .init();
 
-    let state = Arc::new(AppState::new());
+    let store: Arc<dyn DocumentStore> = match config.storage.backend {
+        StorageBackend::Redb => RedbStore::open(config.storage.path.join("documents.redb"))
+            .map(|store| Arc::new(store) as Arc<dyn DocumentStore>),
+        StorageBackend::File => FileStore::new(&config.storage.path)
+            .map(|store| Arc::new(store) as Arc<dyn DocumentStore>),
+    }
+    .unwrap_or_else(|e| exit_with_error(format!("cannot open storage at {}: {}", config.storage.path.display(), e)));
+
+    // 连接认证, 未配置密钥时接受匿名连接
+    let auth = config.authenticator().unwrap_or_else(|e| exit_with_error(e));
+    if auth.is_none() {
+        tracing::warn!("No authentication key configured, accepting anonymous connections");
+    }
+
+    let state = Arc::new(AppState::new(store, &config, auth));
+    tokio::spawn((*state).clone().run_flusher());
+    tokio::spawn((*state).clone().run_reaper());
 
     let app = axum::Router::new()
         .route("/ws", get(websocket_handler))
+        .route("/ws/{doc_id}", get(document_websocket_handler))
+        .route("/documents", get(api::list_documents).post(api::create_document))
+        .route(
+            "/documents/{doc_id}",
+            get(api::get_document).put(api::update_document).delete(api::delete_document),
+        )
+        .route("/documents/{doc_id}/versions", get(api::list_versions))
+        .route("/documents/{doc_id}/versions/{version}", get(api::get_version))
+        .route("/documents/{doc_id}/versions/{version}/restore", post(api::restore_version))
+        .route("/documents/{doc_id}/acl", get(api::get_acl))
+        .route("/documents/{doc_id}/acl/{user_id}", put(api::grant_role).delete(api::revoke_role))
+        .route("/metrics", get(api::metrics))
         .route("/health", get(|| async { "Ok" }))
-        .with_state(state);
+        .layer(DefaultBodyLimit::max(config.limits.max_message_bytes))
+        .with_state(state.clone());
 
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:8080")
+    let addr = config.addr();
+    let tls = tls::load(&config.tls)
         .await
-        .unwrap();
+        .unwrap_or_else(|e| exit_with_error(format!("cannot load TLS certificate: {}", e)));
+
+    match tls {
+        Some(rustls) => {
+            if let (Some(cert), Some(key)) = (config.tls.cert.clone(), config.tls.key.clone()) {
+                tokio::spawn(tls::run_reloader(rustls.clone(), cert, key));
+            }
+
+            let handle = axum_server::Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                async move {
+                    let _ = tokio::signal::ctrl_c().await;
+                    handle.graceful_shutdown(None);
+                }
+            });
+
+            tracing::info!("Server running on https://{}", addr);
+            axum_server::bind_rustls(addr, rustls)
+                .handle(handle)
+                .serve(app.into_make_service())
+                .await
+                .unwrap_or_else(|e| exit_with_error(format!("cannot serve on {}: {}", addr, e)));
+        }
+        None => {
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .unwrap_or_else(|e| exit_with_error(format!("cannot bind {}: {}", addr, e)));
+
+            tracing::info!("Server running on http://{}", listener.local_addr().unwrap_or(addr));
+            axum::serve(listener, app)
+                .with_graceful_shutdown(async {
+                    let _ = tokio::signal::ctrl_c().await;
+                })
+                .await
+                .unwrap();
+        }
+    }
+
+    // 退出前写入尚未保存的编辑
+    state.flush().await;
+    tracing::info!("Server stopped");
+}
 
-    tracing::info!("Server running on http://127.0.0.1:8080");
-    axum::serve(listener, app).await.unwrap();
-}
\ No newline at end of file
+/// 启动失败时输出可读的错误并退出
+fn exit_with_error(error: impl std::fmt::Display) -> ! {
+    eprintln!("error: {}", error);
+    std::process::exit(1);
+}
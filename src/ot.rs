@@ -0,0 +1,478 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::crdt::CrdtError;
+
+/// 操作的单个组成部分, 长度以字符(Unicode 标量)计
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Component {
+    Retain(usize),
+    Insert(String),
+    Delete(usize),
+}
+
+/// 对整篇文档的一次编辑, 由 retain/insert/delete 依次覆盖整个文档
+///
+/// JSON 格式: `[{"retain": 5}, {"insert": "abc"}, {"delete": 2}]`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "Vec<Component>", into = "Vec<Component>")]
+pub struct Operation {
+    components: Vec<Component>,
+}
+
+/// 客户端发来的组件可能有零长度或相邻同类的情况, 统一规整;
+/// retain/delete 总长溢出 `usize` 时拒绝
+impl TryFrom<Vec<Component>> for Operation {
+    type Error = &'static str;
+
+    fn try_from(components: Vec<Component>) -> Result<Self, Self::Error> {
+        components
+            .iter()
+            .try_fold(0usize, |len, c| match c {
+                Component::Retain(n) | Component::Delete(n) => len.checked_add(*n),
+                Component::Insert(_) => Some(len),
+            })
+            .ok_or("operation length overflows")?;
+        let mut op = Operation::new();
+        for component in components {
+            match component {
+                Component::Retain(n) => op.retain(n),
+                Component::Insert(s) => op.insert(&s),
+                Component::Delete(n) => op.delete(n),
+            };
+        }
+        Ok(op)
+    }
+}
+
+impl From<Operation> for Vec<Component> {
+    fn from(op: Operation) -> Self {
+        op.components
+    }
+}
+
+/// 文本中的选区, 以字符偏移表示; `anchor == head` 时即光标
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Selection {
+    pub anchor: usize,
+    pub head: usize,
+}
+
+impl Selection {
+    /// 选区经过操作后的位置, 正好在光标处的插入会把光标推到插入内容之后
+    pub fn transform(self, op: &Operation) -> Self {
+        Self {
+            anchor: op.transform_index(self.anchor),
+            head: op.transform_index(self.head),
+        }
+    }
+
+    /// 把超出文档长度的偏移限制到末尾
+    pub fn clamp(self, len: usize) -> Self {
+        Self {
+            anchor: self.anchor.min(len),
+            head: self.head.min(len),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OtError {
+    /// 操作覆盖的长度与文档长度不一致
+    LengthMismatch { expected: usize, actual: usize },
+    /// 客户端的基础版本比服务器版本还新
+    UnknownVersion { base: u64, current: u64 },
+    /// 基础版本对应的操作已不在内存中, 无法转换
+    VersionTooOld { base: u64, oldest: u64 },
+    /// 文档后端不支持该操作 (例如向 CRDT 文档提交 OT 操作)
+    Unsupported,
+    /// CRDT 操作无法合并或生成
+    Crdt(CrdtError),
+    /// 应用后的文档超出大小上限 (字节)
+    TooLarge { len: usize, max: usize },
+}
+
+impl fmt::Display for OtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OtError::LengthMismatch { expected, actual } => {
+                write!(f, "operation covers {} chars but document has {}", actual, expected)
+            }
+            OtError::UnknownVersion { base, current } => {
+                write!(f, "base version {} is ahead of current version {}", base, current)
+            }
+            OtError::VersionTooOld { base, oldest } => {
+                write!(f, "base version {} is older than the oldest retained version {}", base, oldest)
+            }
+            OtError::Unsupported => write!(f, "document does not accept this kind of operation"),
+            OtError::Crdt(e) => e.fmt(f),
+            OtError::TooLarge { len, max } => {
+                write!(f, "document would grow to {} bytes, the limit is {}", len, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for OtError {}
+
+impl From<CrdtError> for OtError {
+    fn from(e: CrdtError) -> Self {
+        OtError::Crdt(e)
+    }
+}
+
+fn char_len(s: &str) -> usize {
+    s.chars().count()
+}
+
+impl Operation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn components(&self) -> &[Component] {
+        &self.components
+    }
+
+    pub fn retain(&mut self, n: usize) -> &mut Self {
+        if n == 0 {
+            return self;
+        }
+        if let Some(Component::Retain(last)) = self.components.last_mut() {
+            *last += n;
+        } else {
+            self.components.push(Component::Retain(n));
+        }
+        self
+    }
+
+    pub fn insert(&mut self, s: &str) -> &mut Self {
+        if s.is_empty() {
+            return self;
+        }
+        match self.components.as_mut_slice() {
+            [.., Component::Insert(last)] => last.push_str(s),
+            // 插入和删除相邻时, 统一把插入放在删除之前
+            [.., Component::Insert(prev), Component::Delete(_)] => prev.push_str(s),
+            [.., Component::Delete(_)] => {
+                let idx = self.components.len() - 1;
+                self.components.insert(idx, Component::Insert(s.to_string()));
+            }
+            _ => self.components.push(Component::Insert(s.to_string())),
+        }
+        self
+    }
+
+    pub fn delete(&mut self, n: usize) -> &mut Self {
+        if n == 0 {
+            return self;
+        }
+        if let Some(Component::Delete(last)) = self.components.last_mut() {
+            *last += n;
+        } else {
+            self.components.push(Component::Delete(n));
+        }
+        self
+    }
+
+    /// 操作作用前的文档长度
+    pub fn base_len(&self) -> usize {
+        self.components
+            .iter()
+            .map(|c| match c {
+                Component::Retain(n) | Component::Delete(n) => *n,
+                Component::Insert(_) => 0,
+            })
+            .sum()
+    }
+
+    /// 操作作用前的偏移 `index` 在作用后的位置
+    pub fn transform_index(&self, index: usize) -> usize {
+        let mut pos = 0;
+        let mut result = index;
+        for component in &self.components {
+            if pos > index {
+                break;
+            }
+            match component {
+                Component::Retain(n) => pos += n,
+                Component::Insert(s) => result = result.saturating_add(char_len(s)),
+                Component::Delete(n) => {
+                    result -= (*n).min(index - pos);
+                    pos += n;
+                }
+            }
+        }
+        result
+    }
+
+    /// 生成把 `old` 替换为 `new` 的操作, 只删除/插入首尾公共部分之外的字符
+    pub fn diff(old: &str, new: &str) -> Self {
+        let old_chars: Vec<char> = old.chars().collect();
+        let new_chars: Vec<char> = new.chars().collect();
+
+        let prefix = old_chars
+            .iter()
+            .zip(&new_chars)
+            .take_while(|(a, b)| a == b)
+            .count();
+        let suffix = old_chars[prefix..]
+            .iter()
+            .rev()
+            .zip(new_chars[prefix..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        let inserted: String = new_chars[prefix..new_chars.len() - suffix].iter().collect();
+        let mut op = Self::new();
+        op.retain(prefix)
+            .insert(&inserted)
+            .delete(old_chars.len() - suffix - prefix)
+            .retain(suffix);
+        op
+    }
+
+    /// 将操作应用到文本上
+    pub fn apply(&self, text: &str) -> Result<String, OtError> {
+        let expected = char_len(text);
+        if self.base_len() != expected {
+            return Err(OtError::LengthMismatch { expected, actual: self.base_len() });
+        }
+
+        let mut chars = text.chars();
+        let mut result = String::with_capacity(text.len());
+        for component in &self.components {
+            match component {
+                Component::Retain(n) => result.extend(chars.by_ref().take(*n)),
+                Component::Insert(s) => result.push_str(s),
+                Component::Delete(n) => {
+                    chars.by_ref().take(*n).for_each(drop);
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// 转换两个基于同一版本的并发操作, 返回 `(a', b')`,
+    /// 使得 `apply(apply(s, a), b') == apply(apply(s, b), a')`
+    ///
+    /// 同一位置的插入, `a` 的内容排在前面
+    pub fn transform(a: &Operation, b: &Operation) -> Result<(Operation, Operation), OtError> {
+        if a.base_len() != b.base_len() {
+            return Err(OtError::LengthMismatch { expected: a.base_len(), actual: b.base_len() });
+        }
+
+        let mut a_prime = Operation::new();
+        let mut b_prime = Operation::new();
+        let mut iter_a = a.components.iter().cloned();
+        let mut iter_b = b.components.iter().cloned();
+        let mut op_a = iter_a.next();
+        let mut op_b = iter_b.next();
+
+        loop {
+            match (op_a.take(), op_b.take()) {
+                (None, None) => break,
+                (Some(Component::Insert(s)), other) => {
+                    b_prime.retain(char_len(&s));
+                    a_prime.insert(&s);
+                    op_a = iter_a.next();
+                    op_b = other;
+                }
+                (other, Some(Component::Insert(s))) => {
+                    a_prime.retain(char_len(&s));
+                    b_prime.insert(&s);
+                    op_a = other;
+                    op_b = iter_b.next();
+                }
+                (Some(x), Some(y)) => {
+                    let (n, m) = (component_len(&x), component_len(&y));
+                    let min = n.min(m);
+                    match (&x, &y) {
+                        (Component::Retain(_), Component::Retain(_)) => {
+                            a_prime.retain(min);
+                            b_prime.retain(min);
+                        }
+                        (Component::Delete(_), Component::Retain(_)) => {
+                            a_prime.delete(min);
+                        }
+                        (Component::Retain(_), Component::Delete(_)) => {
+                            b_prime.delete(min);
+                        }
+                        // 双方删除了同样的字符, 无需输出
+                        _ => {}
+                    }
+                    op_a = if n > min { Some(with_len(&x, n - min)) } else { iter_a.next() };
+                    op_b = if m > min { Some(with_len(&y, m - min)) } else { iter_b.next() };
+                }
+                // base_len 已校验相等, 不会出现一方先耗尽的情况
+                (Some(_), None) | (None, Some(_)) => {
+                    return Err(OtError::LengthMismatch {
+                        expected: a.base_len(),
+                        actual: b.base_len(),
+                    });
+                }
+            }
+        }
+
+        Ok((a_prime, b_prime))
+    }
+}
+
+fn component_len(component: &Component) -> usize {
+    match component {
+        Component::Retain(n) | Component::Delete(n) => *n,
+        Component::Insert(s) => char_len(s),
+    }
+}
+
+fn with_len(component: &Component, n: usize) -> Component {
+    match component {
+        Component::Retain(_) => Component::Retain(n),
+        Component::Delete(_) => Component::Delete(n),
+        Component::Insert(_) => unreachable!("inserts are consumed whole"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 简单的线性同余生成器, 避免为测试引入随机数依赖
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            ((self.0 >> 33) as usize) % n.max(1)
+        }
+    }
+
+    fn random_text(rng: &mut Lcg, len: usize) -> String {
+        (0..len).map(|_| ['a', 'b', 'c', '中', '文'][rng.below(5)]).collect()
+    }
+
+    /// 生成作用于长度为 `len` 的文本的随机操作
+    fn random_op(rng: &mut Lcg, len: usize) -> Operation {
+        let mut op = Operation::new();
+        let mut remaining = len;
+        while remaining > 0 {
+            let n = 1 + rng.below(remaining);
+            match rng.below(3) {
+                0 => op.retain(n),
+                1 => op.delete(n),
+                _ => {
+                    op.insert(&random_text(rng, n));
+                    continue;
+                }
+            };
+            remaining -= n;
+        }
+        if rng.below(2) == 0 {
+            op.insert(&random_text(rng, 2));
+        }
+        op
+    }
+
+    #[test]
+    fn transform_converges() {
+        let mut rng = Lcg(42);
+        for _ in 0..500 {
+            let len = rng.below(12);
+            let text = random_text(&mut rng, len);
+            let a = random_op(&mut rng, len);
+            let b = random_op(&mut rng, len);
+            let (a_prime, b_prime) = Operation::transform(&a, &b).unwrap();
+            let left = b_prime.apply(&a.apply(&text).unwrap()).unwrap();
+            let right = a_prime.apply(&b.apply(&text).unwrap()).unwrap();
+            assert_eq!(left, right, "text {text:?}, a {a:?}, b {b:?}");
+        }
+    }
+
+    #[test]
+    fn transform_orders_concurrent_inserts() {
+        let mut a = Operation::new();
+        a.retain(1).insert("x").retain(1);
+        let mut b = Operation::new();
+        b.retain(1).insert("y").retain(1);
+        let (a_prime, b_prime) = Operation::transform(&a, &b).unwrap();
+        assert_eq!(b_prime.apply(&a.apply("ab").unwrap()).unwrap(), "axyb");
+        assert_eq!(a_prime.apply(&b.apply("ab").unwrap()).unwrap(), "axyb");
+    }
+
+    #[test]
+    fn transform_rejects_different_base() {
+        let mut a = Operation::new();
+        a.retain(2);
+        let mut b = Operation::new();
+        b.retain(3);
+        assert!(Operation::transform(&a, &b).is_err());
+    }
+
+    #[test]
+    fn diff_round_trips() {
+        let cases = [
+            ("", ""),
+            ("", "hello"),
+            ("hello", ""),
+            ("hello world", "hello brave world"),
+            ("aaaa", "aa"),
+            ("中文文本", "中文的文本"),
+            ("abc", "xyz"),
+        ];
+        for (old, new) in cases {
+            assert_eq!(Operation::diff(old, new).apply(old).unwrap(), new, "{old:?} -> {new:?}");
+        }
+
+        let mut rng = Lcg(7);
+        for _ in 0..200 {
+            let old_len = rng.below(10);
+            let old = random_text(&mut rng, old_len);
+            let new_len = rng.below(10);
+            let new = random_text(&mut rng, new_len);
+            assert_eq!(Operation::diff(&old, &new).apply(&old).unwrap(), new);
+        }
+    }
+
+    #[test]
+    fn diff_keeps_common_affixes() {
+        let op = Operation::diff("hello world", "hello brave world");
+        assert_eq!(
+            op.components(),
+            [Component::Retain(6), Component::Insert("brave ".into()), Component::Retain(5)]
+        );
+    }
+
+    #[test]
+    fn transform_index_follows_edits() {
+        // "hello world" -> "hello, brave world"
+        let mut op = Operation::new();
+        op.retain(5).insert(",").retain(1).insert("brave ").retain(5);
+        assert_eq!(op.transform_index(0), 0);
+        assert_eq!(op.transform_index(5), 6);
+        assert_eq!(op.transform_index(6), 13);
+        assert_eq!(op.transform_index(11), 18);
+
+        // 删除范围内的偏移移到删除起点
+        let mut op = Operation::new();
+        op.retain(2).delete(3).retain(2);
+        assert_eq!(op.transform_index(1), 1);
+        assert_eq!(op.transform_index(3), 2);
+        assert_eq!(op.transform_index(5), 2);
+        assert_eq!(op.transform_index(7), 4);
+    }
+
+    #[test]
+    fn overflowing_lengths_are_rejected() {
+        let json = format!(r#"[{{"retain":{}}},{{"retain":2}}]"#, usize::MAX);
+        assert!(serde_json::from_str::<Operation>(&json).is_err());
+        let json = format!(r#"[{{"delete":{}}},{{"insert":"a"}},{{"retain":1}}]"#, usize::MAX);
+        assert!(serde_json::from_str::<Operation>(&json).is_err());
+
+        let op: Operation =
+            serde_json::from_str(r#"[{"retain":1},{"retain":2},{"insert":"x"}]"#).unwrap();
+        assert_eq!(op.base_len(), 3);
+        assert_eq!(op.transform_index(usize::MAX), usize::MAX);
+    }
+}
@@ -0,0 +1,239 @@
+use serde::{Deserialize, Serialize};
+
+use crate::crdt::{CrdtOp, Element};
+use crate::ot::{Operation, Selection};
+
+/// 服务器支持的协议版本, 从旧到新
+pub const SUPPORTED_VERSIONS: &[u32] = &[1];
+
+/// 服务器支持的可选功能
+pub const FEATURES: &[&str] = &["operation", "crdt", "conflict", "msgpack"];
+
+/// 消息编码, 按连接选择: JSON 文本帧或 MessagePack 二进制帧
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    Msgpack,
+}
+
+/// 握手协商的结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Negotiated {
+    pub version: u32,
+    pub features: Vec<String>,
+    pub encoding: Encoding,
+}
+
+/// 从客户端提供的版本中选出双方都支持的最高版本; 功能取双方的交集,
+/// 客户端未声明功能时启用服务器的全部功能; 未启用 `msgpack` 时编码退回 JSON
+pub fn negotiate(
+    versions: &[u32],
+    features: Option<Vec<String>>,
+    encoding: Encoding,
+) -> Result<Negotiated, String> {
+    let version = versions
+        .iter()
+        .filter(|v| SUPPORTED_VERSIONS.contains(v))
+        .max()
+        .copied()
+        .ok_or_else(|| {
+            format!(
+                "unsupported protocol versions {:?}, server supports {:?}",
+                versions, SUPPORTED_VERSIONS
+            )
+        })?;
+
+    let features: Vec<String> = match features {
+        Some(requested) => requested
+            .into_iter()
+            .filter(|f| FEATURES.contains(&f.as_str()))
+            .collect(),
+        None => FEATURES.iter().map(|f| f.to_string()).collect(),
+    };
+    let encoding = match encoding {
+        Encoding::Msgpack if !features.iter().any(|f| f == "msgpack") => Encoding::Json,
+        encoding => encoding,
+    };
+    Ok(Negotiated { version, features, encoding })
+}
+
+/// 文档中某个用户的身份与状态
+#[derive(Debug, Clone, Serialize)]
+pub struct UserPresence {
+    pub user_id: String,
+    /// 同一用户可能有多个连接, 以此区分
+    pub connection_id: String,
+    pub name: String,
+    pub color: String,
+    /// 连接时间 (Unix 毫秒)
+    pub connected_at: u64,
+    pub selection: Option<Selection>,
+    /// 长时间无活动
+    pub away: bool,
+    /// 最近一次心跳的往返延迟, 尚未测得时为空
+    pub latency_ms: Option<u64>,
+}
+
+/// 文档元数据, 也保存在快照中
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DocumentMetadata {
+    pub title: Option<String>,
+    /// 创建者的用户ID, 未认证时创建的文档为空
+    pub creator: Option<String>,
+    /// 创建时间 (Unix 毫秒), 早期版本创建的文档为 0
+    pub created_at: u64,
+    /// 最后一次编辑的时间 (Unix 毫秒)
+    pub last_modified: u64,
+    /// 最后一次编辑的用户ID
+    pub last_editor: Option<String>,
+    pub tags: Vec<String>,
+    /// 内容的 MIME 类型, 如 `text/markdown`, 客户端据此选择语法高亮
+    pub mime_type: String,
+}
+
+impl Default for DocumentMetadata {
+    fn default() -> Self {
+        Self {
+            title: None,
+            creator: None,
+            created_at: 0,
+            last_modified: 0,
+            last_editor: None,
+            tags: Vec::new(),
+            mime_type: "text/plain".to_string(),
+        }
+    }
+}
+
+/// 恢复会话时补发的一个操作
+#[derive(Debug, Clone, Serialize)]
+pub struct MissedOperation {
+    pub ops: Operation,
+    /// 应用后的文档版本
+    pub version: u64,
+    /// 是否为恢复的连接自己提交的 (即断开前未收到确认的操作)
+    pub own: bool,
+}
+
+/// 客户端 -> 服务器的消息, 格式为 `{"type": "...", "payload": {...}}`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ClientMessage {
+    /// 握手: 客户端支持的协议版本及希望启用的功能
+    Hello {
+        versions: Vec<u32>,
+        #[serde(default)]
+        features: Option<Vec<String>>,
+        // 握手之后服务器发送消息使用的编码, 未指定时沿用连接参数的编码
+        #[serde(default)]
+        encoding: Option<Encoding>,
+    },
+    /// 整体替换内容; 带 `version` 时做乐观并发检查
+    ContentUpdate {
+        content: String,
+        #[serde(default)]
+        version: Option<u64>,
+    },
+    /// 基于 `version` 的 OT 编辑操作
+    Operation { version: u64, ops: Operation },
+    /// CRDT 文档的编辑操作
+    CrdtOperation { ops: Vec<CrdtOp> },
+    /// 光标/选区; 带 `version` 时按其后的操作转换到当前版本
+    CursorPosition {
+        selection: Selection,
+        #[serde(default)]
+        version: Option<u64>,
+    },
+}
+
+/// 服务器 -> 客户端的消息, 格式同 [`ClientMessage`]
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// 握手成功: 协商出的协议版本和功能
+    Welcome { protocol_version: u32, features: Vec<String>, encoding: Encoding },
+    /// OT 文档的完整内容
+    ContentUpdate { content: String, version: u64 },
+    /// CRDT 文档的完整状态, 连接时附带分配给该客户端的站点ID
+    CrdtSnapshot {
+        content: String,
+        version: u64,
+        elements: Vec<Element>,
+        site_id: Option<String>,
+    },
+    /// 转换后已提交的 OT 操作
+    Operation { ops: Operation, version: u64, user_id: String, connection_id: String },
+    /// 已合并的 CRDT 操作
+    CrdtOperation { ops: Vec<CrdtOp>, version: u64, user_id: String, connection_id: String },
+    /// 其他用户的光标/选区, 基于文档版本 `version`
+    CursorPosition { user_id: String, connection_id: String, selection: Selection, version: u64 },
+    /// 会话令牌, 断开后可凭此重连并恢复身份; `resumed` 表示本连接恢复了之前的会话
+    Session { resume_token: String, resumed: bool },
+    /// 恢复会话时代替完整快照: 客户端确认的版本之后错过的操作
+    MissedOperations { operations: Vec<MissedOperation>, version: u64 },
+    /// 基础版本已过期, 附带最新内容供客户端变基
+    Conflict { content: String, version: u64 },
+    UserCountUpdate { count: usize },
+    /// 有用户加入文档
+    UserJoined(UserPresence),
+    /// 用户状态变化, 如空闲后标记为离开
+    PresenceUpdate(UserPresence),
+    /// 有用户离开文档
+    UserLeft { user_id: String, connection_id: String },
+    /// 连接时发送: 文档中的全部用户 (含自己), `user_id`/`connection_id` 为本连接的身份
+    PresenceSnapshot { user_id: String, connection_id: String, users: Vec<UserPresence> },
+    /// 连接时及标题、标签等修改后发送; 编辑不会单独发送此消息
+    Metadata(DocumentMetadata),
+    /// 请求无法处理
+    Error { code: ErrorCode, reason: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// 消息无法解析或不符合协议
+    InvalidMessage,
+    /// 编辑操作无法应用到文档
+    InvalidOperation,
+    /// 文档后端不支持该消息
+    Unsupported,
+    /// 没有双方都支持的协议版本, 随后连接将被关闭
+    UnsupportedVersion,
+    /// 用户在文档中的角色不允许该操作
+    Forbidden,
+    /// 消息过于频繁, 已被丢弃; 持续超出限制的连接将被关闭
+    RateLimited,
+    /// 消息或编辑后的文档超出大小上限, 已被拒绝
+    PayloadTooLarge,
+    /// 文档已被删除, 随后连接将被关闭
+    NotFound,
+}
+
+impl ClientMessage {
+    pub fn from_json(text: &str) -> Result<Self, String> {
+        serde_json::from_str(text).map_err(|e| e.to_string())
+    }
+
+    pub fn from_msgpack(bytes: &[u8]) -> Result<Self, String> {
+        rmp_serde::from_slice(bytes).map_err(|e| e.to_string())
+    }
+}
+
+impl ServerMessage {
+    pub fn error(code: ErrorCode, reason: impl Into<String>) -> Self {
+        ServerMessage::Error { code, reason: reason.into() }
+    }
+
+    pub fn to_json(&self) -> String {
+        // 消息只包含字符串、数字和可序列化的枚举, 不会失败
+        serde_json::to_string(self).expect("server message is always serializable")
+    }
+
+    /// MessagePack 编码, 结构体按字段名编码以与 JSON 格式保持一致
+    pub fn to_msgpack(&self) -> Vec<u8> {
+        rmp_serde::to_vec_named(self).expect("server message is always serializable")
+    }
+}
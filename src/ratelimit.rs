@@ -0,0 +1,196 @@
+use std::time::{Duration, Instant};
+
+use crate::config::RateLimitConfig;
+
+/// 令牌桶: 每秒补充 `rate` 个令牌, 最多积累 `burst` 个
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// `rate` 为 0 时不限制
+    fn new(rate: u64, burst: u64, now: Instant) -> Option<Self> {
+        (rate > 0).then_some(Self {
+            rate: rate as f64,
+            burst: burst as f64,
+            tokens: burst as f64,
+            updated: now,
+        })
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated = now;
+    }
+}
+
+/// 对一条消息的处理结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// 超出限制, 丢弃该消息
+    Reject,
+    /// 窗口内超出限制的次数过多, 应断开连接
+    Disconnect,
+}
+
+/// 单个连接的消息限流: 消息数和字节数各一个令牌桶, 两者都有余量时才接受消息
+#[derive(Debug)]
+pub struct RateLimiter {
+    messages: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    max_violations: u32,
+    violation_window: Duration,
+    violations: u32,
+    window_started: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            messages: TokenBucket::new(config.messages_per_sec, config.message_burst, now),
+            bytes: TokenBucket::new(config.bytes_per_sec, config.byte_burst, now),
+            max_violations: config.max_violations,
+            violation_window: config.violation_window(),
+            violations: 0,
+            window_started: now,
+        }
+    }
+
+    /// 收到一条 `len` 字节的消息
+    pub fn check(&mut self, len: usize) -> Verdict {
+        self.check_at(len, Instant::now())
+    }
+
+    fn check_at(&mut self, len: usize, now: Instant) -> Verdict {
+        let len = len as f64;
+        for bucket in [&mut self.messages, &mut self.bytes].into_iter().flatten() {
+            bucket.refill(now);
+        }
+        let allowed = self.messages.as_ref().is_none_or(|bucket| bucket.tokens >= 1.0)
+            && self.bytes.as_ref().is_none_or(|bucket| bucket.tokens >= len);
+        if allowed {
+            if let Some(bucket) = self.messages.as_mut() {
+                bucket.tokens -= 1.0;
+            }
+            if let Some(bucket) = self.bytes.as_mut() {
+                bucket.tokens -= len;
+            }
+            return Verdict::Allow;
+        }
+
+        if now.duration_since(self.window_started) >= self.violation_window {
+            self.window_started = now;
+            self.violations = 0;
+        }
+        self.violations += 1;
+        if self.violations > self.max_violations {
+            Verdict::Disconnect
+        } else {
+            Verdict::Reject
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(change: impl FnOnce(&mut RateLimitConfig)) -> (RateLimiter, Instant) {
+        let mut config = RateLimitConfig {
+            messages_per_sec: 0,
+            message_burst: 0,
+            bytes_per_sec: 0,
+            byte_burst: 0,
+            max_violations: 100,
+            violation_window_secs: 10,
+        };
+        change(&mut config);
+        let limiter = RateLimiter::new(&config);
+        let start = limiter.window_started;
+        (limiter, start)
+    }
+
+    #[test]
+    fn message_bucket_allows_burst_then_refills() {
+        let (mut limiter, start) = limiter(|c| {
+            c.messages_per_sec = 2;
+            c.message_burst = 3;
+        });
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at(1, start), Verdict::Allow);
+        }
+        assert_eq!(limiter.check_at(1, start), Verdict::Reject);
+
+        // 每秒 2 个, 半秒补充 1 个; 积累不超过突发上限
+        let later = start + Duration::from_millis(500);
+        assert_eq!(limiter.check_at(1, later), Verdict::Allow);
+        assert_eq!(limiter.check_at(1, later), Verdict::Reject);
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at(1, later), Verdict::Allow);
+        }
+        assert_eq!(limiter.check_at(1, later), Verdict::Reject);
+    }
+
+    #[test]
+    fn byte_bucket_limits_by_size() {
+        let (mut limiter, start) = limiter(|c| {
+            c.bytes_per_sec = 100;
+            c.byte_burst = 100;
+        });
+        assert_eq!(limiter.check_at(60, start), Verdict::Allow);
+        assert_eq!(limiter.check_at(60, start), Verdict::Reject);
+        assert_eq!(limiter.check_at(40, start), Verdict::Allow);
+
+        let later = start + Duration::from_secs(1);
+        assert_eq!(limiter.check_at(101, later), Verdict::Reject);
+        assert_eq!(limiter.check_at(100, later), Verdict::Allow);
+    }
+
+    #[test]
+    fn repeated_violations_disconnect() {
+        let (mut limiter, start) = limiter(|c| {
+            c.messages_per_sec = 1;
+            c.message_burst = 1;
+            c.max_violations = 2;
+        });
+        assert_eq!(limiter.check_at(1, start), Verdict::Allow);
+        assert_eq!(limiter.check_at(1, start), Verdict::Reject);
+        assert_eq!(limiter.check_at(1, start), Verdict::Reject);
+        assert_eq!(limiter.check_at(1, start), Verdict::Disconnect);
+    }
+
+    #[test]
+    fn violations_reset_after_window() {
+        let (mut limiter, start) = limiter(|c| {
+            c.messages_per_sec = 1;
+            c.message_burst = 1;
+            c.max_violations = 2;
+        });
+        assert_eq!(limiter.check_at(1, start), Verdict::Allow);
+        assert_eq!(limiter.check_at(1, start), Verdict::Reject);
+        assert_eq!(limiter.check_at(1, start), Verdict::Reject);
+
+        // 新窗口重新计数
+        let later = start + Duration::from_secs(10);
+        assert_eq!(limiter.check_at(1, later), Verdict::Allow);
+        assert_eq!(limiter.check_at(1, later), Verdict::Reject);
+        assert_eq!(limiter.check_at(1, later), Verdict::Reject);
+        assert_eq!(limiter.check_at(1, later), Verdict::Disconnect);
+    }
+
+    #[test]
+    fn zero_rate_is_unlimited() {
+        let (mut limiter, start) = limiter(|_| {});
+        for _ in 0..1000 {
+            assert_eq!(limiter.check_at(usize::MAX, start), Verdict::Allow);
+        }
+    }
+}
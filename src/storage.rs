@@ -0,0 +1,567 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use redb::{Database, ReadableTable, TableDefinition};
+use serde::{Deserialize, Serialize};
+
+use crate::acl::Acl;
+use crate::app::DocumentKind;
+use crate::crdt::{CrdtOp, Element};
+use crate::ot::Operation;
+use crate::protocol::DocumentMetadata;
+
+/// 文档的完整快照
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DocumentSnapshot {
+    pub kind: DocumentKind,
+    pub content: String,
+    pub version: u64,
+    // CRDT 文档需要保存全部元素 (含墓碑) 才能继续合并
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub elements: Option<Vec<Element>>,
+    // 旧快照没有访问控制列表, 按默认 (所有人可编辑) 处理
+    #[serde(default)]
+    pub acl: Acl,
+    #[serde(default)]
+    pub metadata: DocumentMetadata,
+}
+
+/// 一次已接受的编辑, `version` 为应用后的文档版本
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub version: u64,
+    // 编辑时间, Unix 毫秒
+    #[serde(default)]
+    pub timestamp: u64,
+    // 编辑者的用户ID, 未认证的 REST 编辑等没有记录
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub editor: Option<String>,
+    #[serde(flatten)]
+    pub op: LoggedOp,
+}
+
+impl LogEntry {
+    pub fn new(version: u64, op: LoggedOp, editor: Option<String>) -> Self {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        Self { version, timestamp, editor, op }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LoggedOp {
+    Ot { ops: Operation },
+    Crdt { ops: Vec<CrdtOp> },
+}
+
+/// 文档持久化存储
+///
+/// 方法是同步的, 调用方应在阻塞线程池中执行
+pub trait DocumentStore: Send + Sync + std::fmt::Debug {
+    /// 读取文档快照, 文档不存在时返回 `None`
+    fn load(&self, doc_id: &str) -> io::Result<Option<DocumentSnapshot>>;
+
+    /// 保存文档快照, 覆盖之前的快照
+    fn save_snapshot(&self, doc_id: &str, snapshot: &DocumentSnapshot) -> io::Result<()>;
+
+    /// 按顺序追加编辑记录, 返回前记录已同步到磁盘
+    fn append_ops(&self, doc_id: &str, entries: &[LogEntry]) -> io::Result<()>;
+
+    /// 按版本顺序读取 `after_version` 之后的编辑记录, 用于恢复文档;
+    /// 日志尾部有崩溃留下的不完整记录时将其截掉, 之后的追加不会接在残缺的行后面
+    fn load_log(&self, doc_id: &str, after_version: u64) -> io::Result<Vec<LogEntry>>;
+
+    /// 把版本不超过 `up_to_version` 的编辑记录 (已包含在快照中) 移出日志, 归档为历史
+    fn truncate_log(&self, doc_id: &str, up_to_version: u64) -> io::Result<()>;
+
+    /// 按版本顺序读取从头到 `up_to_version` 的全部编辑记录 (历史 + 日志)
+    fn load_history(&self, doc_id: &str, up_to_version: u64) -> io::Result<Vec<LogEntry>>;
+
+    /// 存储中全部文档的ID (以快照为准)
+    fn list(&self) -> io::Result<Vec<String>>;
+
+    /// 删除文档的快照、日志和历史, 文档不存在时什么都不做
+    fn delete(&self, doc_id: &str) -> io::Result<()>;
+}
+
+/// 合并历史与日志中的记录: 截断过程中崩溃可能使同一版本同时出现在两处
+fn merge_history(history: Vec<LogEntry>, log: Vec<LogEntry>, up_to_version: u64) -> Vec<LogEntry> {
+    let mut entries: Vec<LogEntry> = Vec::with_capacity(history.len() + log.len());
+    for entry in history.into_iter().chain(log) {
+        if entry.version > up_to_version {
+            break;
+        }
+        if entries.last().is_none_or(|last| entry.version > last.version) {
+            entries.push(entry);
+        }
+    }
+    entries
+}
+
+/// 文件存储的日志可能因崩溃留下不完整的最后一行, 读到无法解析的行即停止
+///
+/// 返回读到的记录和完整记录占用的字节数
+fn parse_log_lines(mut reader: impl BufRead, doc_id: &str) -> io::Result<(Vec<LogEntry>, u64)> {
+    let mut entries = Vec::new();
+    let mut valid_len = 0;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let n = reader.read_until(b'\n', &mut line)?;
+        if n == 0 {
+            break;
+        }
+        if line.last() != Some(&b'\n') {
+            tracing::warn!(doc_id = %doc_id, "Ignoring incomplete log tail of {} bytes", n);
+            break;
+        }
+        match serde_json::from_slice::<LogEntry>(&line) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => {
+                tracing::warn!(doc_id = %doc_id, "Ignoring unreadable log tail: {}", e);
+                break;
+            }
+        }
+        valid_len += n as u64;
+    }
+    Ok((entries, valid_len))
+}
+
+/// 文件系统存储: 每个文档一个快照文件 `{id}.json`、一个 JSON Lines 预写日志 `{id}.log`
+/// 和截断后归档的历史 `{id}.history`
+#[derive(Debug)]
+pub struct FileStore {
+    root: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(root: impl Into<PathBuf>) -> io::Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+        Ok(Self { root })
+    }
+
+    fn path(&self, doc_id: &str, extension: &str) -> PathBuf {
+        self.root.join(format!("{}.{}", file_stem(doc_id), extension))
+    }
+}
+
+/// 文档ID可能来自 URL, 除字母数字、`-`、`_` 外全部转义, 避免路径穿越
+fn file_stem(doc_id: &str) -> String {
+    let mut stem = String::with_capacity(doc_id.len());
+    for byte in doc_id.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' {
+            stem.push(byte as char);
+        } else {
+            stem.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    stem
+}
+
+/// [`file_stem`] 的逆过程, 不是由其生成的文件名返回 `None`
+fn doc_id_from_stem(stem: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(stem.len());
+    let mut rest = stem.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+impl DocumentStore for FileStore {
+    fn load(&self, doc_id: &str) -> io::Result<Option<DocumentSnapshot>> {
+        match fs::read(self.path(doc_id, "json")) {
+            Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn save_snapshot(&self, doc_id: &str, snapshot: &DocumentSnapshot) -> io::Result<()> {
+        // 先写临时文件再重命名, 保证快照文件总是完整的
+        let path = self.path(doc_id, "json");
+        let tmp = self.path(doc_id, "json.tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(snapshot)?)?;
+        file.sync_all()?;
+        fs::rename(tmp, path)
+    }
+
+    fn append_ops(&self, doc_id: &str, entries: &[LogEntry]) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(doc_id, "log"))?;
+        let mut lines = Vec::new();
+        for entry in entries {
+            serde_json::to_writer(&mut lines, entry)?;
+            lines.push(b'\n');
+        }
+        file.write_all(&lines)?;
+        file.sync_data()
+    }
+
+    fn load_log(&self, doc_id: &str, after_version: u64) -> io::Result<Vec<LogEntry>> {
+        let file = match OpenOptions::new().read(true).write(true).open(self.path(doc_id, "log")) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let (mut entries, valid_len) = parse_log_lines(BufReader::new(&file), doc_id)?;
+        if valid_len < file.metadata()?.len() {
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+            tracing::warn!(doc_id = %doc_id, "Truncated log to {} bytes", valid_len);
+        }
+        entries.retain(|entry| entry.version > after_version);
+        Ok(entries)
+    }
+
+    fn truncate_log(&self, doc_id: &str, up_to_version: u64) -> io::Result<()> {
+        let (archived, tail): (Vec<_>, Vec<_>) = self
+            .read_lines(doc_id, "log")?
+            .into_iter()
+            .partition(|entry| entry.version <= up_to_version);
+
+        // 先归档, 再把保留的尾部写入临时文件替换原日志
+        let mut history = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(doc_id, "history"))?;
+        for entry in &archived {
+            let mut line = serde_json::to_vec(entry)?;
+            line.push(b'\n');
+            history.write_all(&line)?;
+        }
+        history.sync_all()?;
+
+        let path = self.path(doc_id, "log");
+        let tmp = self.path(doc_id, "log.tmp");
+        let mut file = File::create(&tmp)?;
+        for entry in &tail {
+            let mut line = serde_json::to_vec(entry)?;
+            line.push(b'\n');
+            file.write_all(&line)?;
+        }
+        file.sync_all()?;
+        fs::rename(tmp, path)
+    }
+
+    fn load_history(&self, doc_id: &str, up_to_version: u64) -> io::Result<Vec<LogEntry>> {
+        let history = self.read_lines(doc_id, "history")?;
+        let log = self.read_lines(doc_id, "log")?;
+        Ok(merge_history(history, log, up_to_version))
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        let mut doc_ids = Vec::new();
+        for entry in fs::read_dir(&self.root)? {
+            let name = entry?.file_name();
+            let Some(stem) = name.to_str().and_then(|name| name.strip_suffix(".json")) else {
+                continue;
+            };
+            if let Some(doc_id) = doc_id_from_stem(stem) {
+                doc_ids.push(doc_id);
+            }
+        }
+        Ok(doc_ids)
+    }
+
+    fn delete(&self, doc_id: &str) -> io::Result<()> {
+        // 先删快照, 中途失败时剩下的日志不会使文档出现在列表中
+        for extension in ["json", "log", "history"] {
+            match fs::remove_file(self.path(doc_id, extension)) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FileStore {
+    fn read_lines(&self, doc_id: &str, extension: &str) -> io::Result<Vec<LogEntry>> {
+        match File::open(self.path(doc_id, extension)) {
+            Ok(file) => parse_log_lines(BufReader::new(file), doc_id).map(|(entries, _)| entries),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+const SNAPSHOTS: TableDefinition<&str, &[u8]> = TableDefinition::new("snapshots");
+const OPERATIONS: TableDefinition<(&str, u64), &[u8]> = TableDefinition::new("operations");
+const HISTORY: TableDefinition<(&str, u64), &[u8]> = TableDefinition::new("history");
+
+/// 嵌入式数据库存储 (redb), 快照、编辑记录和历史分表保存, 后两者以 (文档ID, 版本) 为键
+#[derive(Debug)]
+pub struct RedbStore {
+    db: Database,
+}
+
+impl RedbStore {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        if let Some(parent) = path.as_ref().parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let db = Database::create(path).map_err(db_error)?;
+        Ok(Self { db })
+    }
+}
+
+fn db_error(e: impl Into<redb::Error>) -> io::Error {
+    io::Error::other(e.into())
+}
+
+impl DocumentStore for RedbStore {
+    fn load(&self, doc_id: &str) -> io::Result<Option<DocumentSnapshot>> {
+        let txn = self.db.begin_read().map_err(db_error)?;
+        let table = match txn.open_table(SNAPSHOTS) {
+            Ok(table) => table,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(None),
+            Err(e) => return Err(db_error(e)),
+        };
+        match table.get(doc_id).map_err(db_error)? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(bytes.value())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn save_snapshot(&self, doc_id: &str, snapshot: &DocumentSnapshot) -> io::Result<()> {
+        let bytes = serde_json::to_vec(snapshot)?;
+        let txn = self.db.begin_write().map_err(db_error)?;
+        {
+            let mut table = txn.open_table(SNAPSHOTS).map_err(db_error)?;
+            table.insert(doc_id, bytes.as_slice()).map_err(db_error)?;
+        }
+        txn.commit().map_err(db_error)
+    }
+
+    fn append_ops(&self, doc_id: &str, entries: &[LogEntry]) -> io::Result<()> {
+        let txn = self.db.begin_write().map_err(db_error)?;
+        {
+            let mut table = txn.open_table(OPERATIONS).map_err(db_error)?;
+            for entry in entries {
+                let bytes = serde_json::to_vec(entry)?;
+                table.insert((doc_id, entry.version), bytes.as_slice()).map_err(db_error)?;
+            }
+        }
+        txn.commit().map_err(db_error)
+    }
+
+    fn load_log(&self, doc_id: &str, after_version: u64) -> io::Result<Vec<LogEntry>> {
+        self.read_range(OPERATIONS, doc_id, after_version + 1, u64::MAX)
+    }
+
+    fn truncate_log(&self, doc_id: &str, up_to_version: u64) -> io::Result<()> {
+        // 同一事务内把记录从日志表移到历史表
+        let txn = self.db.begin_write().map_err(db_error)?;
+        {
+            let mut operations = txn.open_table(OPERATIONS).map_err(db_error)?;
+            let mut history = txn.open_table(HISTORY).map_err(db_error)?;
+            let mut archived = Vec::new();
+            for item in operations.range((doc_id, 0)..=(doc_id, up_to_version)).map_err(db_error)? {
+                let (key, bytes) = item.map_err(db_error)?;
+                archived.push((key.value().1, bytes.value().to_vec()));
+            }
+            for (version, bytes) in &archived {
+                history.insert((doc_id, *version), bytes.as_slice()).map_err(db_error)?;
+            }
+            operations
+                .retain_in((doc_id, 0)..=(doc_id, up_to_version), |_, _| false)
+                .map_err(db_error)?;
+        }
+        txn.commit().map_err(db_error)
+    }
+
+    fn load_history(&self, doc_id: &str, up_to_version: u64) -> io::Result<Vec<LogEntry>> {
+        let history = self.read_range(HISTORY, doc_id, 0, up_to_version)?;
+        let log = self.read_range(OPERATIONS, doc_id, 0, up_to_version)?;
+        Ok(merge_history(history, log, up_to_version))
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        let txn = self.db.begin_read().map_err(db_error)?;
+        let table = match txn.open_table(SNAPSHOTS) {
+            Ok(table) => table,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(Vec::new()),
+            Err(e) => return Err(db_error(e)),
+        };
+        let mut doc_ids = Vec::new();
+        for item in table.iter().map_err(db_error)? {
+            let (key, _) = item.map_err(db_error)?;
+            doc_ids.push(key.value().to_string());
+        }
+        Ok(doc_ids)
+    }
+
+    fn delete(&self, doc_id: &str) -> io::Result<()> {
+        let txn = self.db.begin_write().map_err(db_error)?;
+        {
+            let mut snapshots = txn.open_table(SNAPSHOTS).map_err(db_error)?;
+            snapshots.remove(doc_id).map_err(db_error)?;
+            for definition in [OPERATIONS, HISTORY] {
+                let mut table = txn.open_table(definition).map_err(db_error)?;
+                table
+                    .retain_in((doc_id, 0)..=(doc_id, u64::MAX), |_, _| false)
+                    .map_err(db_error)?;
+            }
+        }
+        txn.commit().map_err(db_error)
+    }
+}
+
+impl RedbStore {
+    fn read_range(
+        &self,
+        definition: TableDefinition<(&str, u64), &[u8]>,
+        doc_id: &str,
+        from_version: u64,
+        to_version: u64,
+    ) -> io::Result<Vec<LogEntry>> {
+        let txn = self.db.begin_read().map_err(db_error)?;
+        let table = match txn.open_table(definition) {
+            Ok(table) => table,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(Vec::new()),
+            Err(e) => return Err(db_error(e)),
+        };
+        let mut entries = Vec::new();
+        for item in table.range((doc_id, from_version)..=(doc_id, to_version)).map_err(db_error)? {
+            let (_, bytes) = item.map_err(db_error)?;
+            entries.push(serde_json::from_slice(bytes.value())?);
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("realtime-editor-test-{}", uuid::Uuid::new_v4()))
+    }
+
+    fn entry(version: u64) -> LogEntry {
+        let mut ops = Operation::new();
+        ops.insert(&version.to_string());
+        LogEntry::new(version, LoggedOp::Ot { ops }, None)
+    }
+
+    fn versions(entries: &[LogEntry]) -> Vec<u64> {
+        entries.iter().map(|entry| entry.version).collect()
+    }
+
+    fn lines(entries: &[LogEntry]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for entry in entries {
+            serde_json::to_writer(&mut bytes, entry).unwrap();
+            bytes.push(b'\n');
+        }
+        bytes
+    }
+
+    #[test]
+    fn parse_log_lines_stops_at_torn_tail() {
+        let valid = lines(&[entry(1), entry(2)]);
+
+        let (entries, valid_len) = parse_log_lines(valid.as_slice(), "doc").unwrap();
+        assert_eq!(versions(&entries), [1, 2]);
+        assert_eq!(valid_len, valid.len() as u64);
+
+        // 没有换行的残缺行和无法解析的行都不计入
+        for tail in [&b"{\"version\":3,\"ty"[..], b"garbage\n"] {
+            let bytes = [valid.as_slice(), tail, &lines(&[entry(3)])].concat();
+            let (entries, valid_len) = parse_log_lines(bytes.as_slice(), "doc").unwrap();
+            assert_eq!(versions(&entries), [1, 2]);
+            assert_eq!(valid_len, valid.len() as u64);
+        }
+    }
+
+    #[test]
+    fn file_store_truncates_torn_log_tail() {
+        let dir = temp_dir();
+        let store = FileStore::new(&dir).unwrap();
+        store.append_ops("doc", &[entry(1), entry(2)]).unwrap();
+        let path = dir.join("doc.log");
+        let valid_len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(b"{\"version\":3,\"ty").unwrap();
+
+        assert_eq!(versions(&store.load_log("doc", 0).unwrap()), [1, 2]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), valid_len);
+
+        // 之后的追加不会接在残缺的行后面
+        store.append_ops("doc", &[entry(3)]).unwrap();
+        assert_eq!(versions(&store.load_log("doc", 0).unwrap()), [1, 2, 3]);
+        assert_eq!(versions(&store.load_log("doc", 2).unwrap()), [3]);
+    }
+
+    #[test]
+    fn history_skips_entries_left_in_log_by_interrupted_truncate() {
+        let merged = merge_history(vec![entry(1), entry(2), entry(3)], vec![entry(3), entry(4)], u64::MAX);
+        assert_eq!(versions(&merged), [1, 2, 3, 4]);
+        let merged = merge_history(vec![entry(1), entry(2), entry(3)], vec![entry(3), entry(4)], 2);
+        assert_eq!(versions(&merged), [1, 2]);
+
+        // 归档写完后、日志替换前崩溃
+        let dir = temp_dir();
+        let store = FileStore::new(&dir).unwrap();
+        store.append_ops("doc", &[entry(1), entry(2), entry(3), entry(4)]).unwrap();
+        fs::write(dir.join("doc.history"), lines(&[entry(1), entry(2), entry(3)])).unwrap();
+        assert_eq!(versions(&store.load_history("doc", u64::MAX).unwrap()), [1, 2, 3, 4]);
+        assert_eq!(versions(&store.load_history("doc", 3).unwrap()), [1, 2, 3]);
+    }
+
+    fn check_store(store: &dyn DocumentStore) {
+        assert!(store.load("doc").unwrap().is_none());
+        assert!(store.load_log("doc", 0).unwrap().is_empty());
+
+        store.append_ops("doc", &[entry(1), entry(2), entry(3)]).unwrap();
+        let snapshot = DocumentSnapshot {
+            kind: DocumentKind::Ot,
+            content: "123".to_string(),
+            version: 3,
+            elements: None,
+            acl: Acl::owned_by("alice"),
+            metadata: DocumentMetadata::default(),
+        };
+        store.save_snapshot("doc", &snapshot).unwrap();
+        store.truncate_log("doc", 2).unwrap();
+        store.append_ops("doc", &[entry(4), entry(5)]).unwrap();
+
+        let loaded = store.load("doc").unwrap().unwrap();
+        assert_eq!((loaded.content.as_str(), loaded.version), ("123", 3));
+        assert_eq!(loaded.acl, snapshot.acl);
+        assert_eq!(versions(&store.load_log("doc", 3).unwrap()), [4, 5]);
+        assert_eq!(versions(&store.load_history("doc", u64::MAX).unwrap()), [1, 2, 3, 4, 5]);
+        assert_eq!(versions(&store.load_history("doc", 4).unwrap()), [1, 2, 3, 4]);
+        assert_eq!(store.list().unwrap(), ["doc"]);
+
+        store.delete("doc").unwrap();
+        assert!(store.load("doc").unwrap().is_none());
+        assert!(store.load_history("doc", u64::MAX).unwrap().is_empty());
+        assert!(store.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn file_store_round_trip() {
+        check_store(&FileStore::new(temp_dir()).unwrap());
+    }
+
+    #[test]
+    fn redb_store_round_trip() {
+        check_store(&RedbStore::open(temp_dir().join("documents.redb")).unwrap());
+    }
+}
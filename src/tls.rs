@@ -0,0 +1,56 @@
+use std::io;
+use std::path::PathBuf;
+
+use axum_server::tls_rustls::RustlsConfig;
+
+use crate::config::TlsConfig;
+
+/// 开发模式自签名证书包含的主机名
+const SELF_SIGNED_NAMES: &[&str] = &["localhost", "127.0.0.1", "::1"];
+
+/// 按配置加载证书, 未启用 TLS 时返回 `None`
+pub async fn load(config: &TlsConfig) -> io::Result<Option<RustlsConfig>> {
+    if config.self_signed {
+        tracing::warn!("Using a self-signed certificate for {:?}, for local development only", SELF_SIGNED_NAMES);
+        let names: Vec<String> = SELF_SIGNED_NAMES.iter().map(|name| name.to_string()).collect();
+        let certified = rcgen::generate_simple_self_signed(names).map_err(io::Error::other)?;
+        let rustls = RustlsConfig::from_pem(
+            certified.cert.pem().into_bytes(),
+            certified.signing_key.serialize_pem().into_bytes(),
+        )
+        .await?;
+        return Ok(Some(rustls));
+    }
+
+    match (&config.cert, &config.key) {
+        (Some(cert), Some(key)) => RustlsConfig::from_pem_file(cert, key).await.map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// 收到 SIGHUP 时重新读取证书文件, 用于证书续期后无需重启; 读取失败时继续使用旧证书
+pub async fn run_reloader(rustls: RustlsConfig, cert: PathBuf, key: PathBuf) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGHUP, certificate reload disabled: {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            match rustls.reload_from_pem_file(&cert, &key).await {
+                Ok(()) => tracing::info!("Reloaded TLS certificate from {}", cert.display()),
+                Err(e) => tracing::error!("Failed to reload TLS certificate, keeping the old one: {}", e),
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (rustls, cert, key);
+        tracing::warn!("Certificate reload on SIGHUP is only supported on unix");
+    }
+}